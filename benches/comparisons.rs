@@ -3,9 +3,9 @@
 extern crate test;
 
 use futures::stream::{iter, StreamExt};
-use impedance::adaptive::{AdaptiveFuture, Token};
+use impedance::adaptive::{AdaptiveFuture, AdaptiveState, Outcome, SchedulingPolicy, Token};
 use once_cell::sync::Lazy;
-use std::future::Future;
+use std::{collections::HashMap, future::Future, sync::Mutex};
 use test::{black_box, Bencher};
 
 fn slow(idx: usize) -> usize {
@@ -45,11 +45,13 @@ fn benchmark<B: Fn(usize) -> usize + Copy, W: Fn(usize, B) -> F + Copy, F: Futur
     });
 }
 
-static TOKEN: Lazy<Token> = Lazy::new(|| Token::new());
+static TOKEN: Lazy<Token> = Lazy::new(Token::new);
 
 #[bench]
 fn slow_with_adaptive(b: &mut Bencher) {
-    benchmark(b, slow, |i, f| AdaptiveFuture::new(*TOKEN, move || f(i)));
+    benchmark(b, slow, |i, f| {
+        AdaptiveFuture::new(TOKEN.clone(), move || f(i))
+    });
 }
 
 #[bench]
@@ -73,7 +75,9 @@ fn slow_with_spawn(b: &mut Bencher) {
 
 #[bench]
 fn fast_with_adaptive(b: &mut Bencher) {
-    benchmark(b, fast, |i, f| AdaptiveFuture::new(*TOKEN, move || f(i)));
+    benchmark(b, fast, |i, f| {
+        AdaptiveFuture::new(TOKEN.clone(), move || f(i))
+    });
 }
 
 #[bench]
@@ -101,3 +105,91 @@ fn fast_with_adaptive_always_spawn(b: &mut Bencher) {
         AdaptiveFuture::new(Token::always_spawn(), move || f(i))
     });
 }
+
+// Many tasks on many worker threads sharing a single `static` `Token`, which is the case where
+// a global lock around token state would be contended.
+const CONTENDED_TASKS: usize = 64;
+const CONTENDED_CALLS: usize = 100;
+
+fn tiny(idx: usize) -> usize {
+    black_box(idx)
+}
+
+fn contended<W: Fn(usize) -> F + Copy + Send + 'static, F: Future<Output = usize> + Send>(
+    b: &mut Bencher,
+    wrapper: W,
+) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+    b.iter(|| {
+        runtime.block_on(async {
+            let tasks: Vec<_> = (0..CONTENDED_TASKS)
+                .map(|t| {
+                    tokio::spawn(async move {
+                        let mut sum = 0;
+                        for i in 0..CONTENDED_CALLS {
+                            sum += wrapper(t * CONTENDED_CALLS + i).await;
+                        }
+                        sum
+                    })
+                })
+                .collect();
+
+            for task in tasks {
+                black_box(task.await.unwrap());
+            }
+        });
+    });
+}
+
+static CONTENDED_TOKEN: Lazy<Token> = Lazy::new(Token::new);
+
+#[bench]
+fn contended_with_adaptive(b: &mut Bencher) {
+    contended(b, |i| {
+        AdaptiveFuture::new(CONTENDED_TOKEN.clone(), move || tiny(i))
+    });
+}
+
+/// What `Token`'s used to do: look up their state in a global map behind a mutex, for every
+/// decision and every observation
+struct GlobalMutex;
+
+static GLOBAL_STATE: Lazy<Mutex<HashMap<usize, bool>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+impl SchedulingPolicy for GlobalMutex {
+    fn decide(&self) -> AdaptiveState {
+        if *GLOBAL_STATE.lock().unwrap().entry(0).or_default() {
+            AdaptiveState::Spawn
+        } else {
+            AdaptiveState::Inline
+        }
+    }
+
+    fn observe(&self, outcome: &Outcome) {
+        GLOBAL_STATE
+            .lock()
+            .unwrap()
+            .insert(0, outcome.elapsed > outcome.cutoff);
+    }
+}
+
+static GLOBAL_MUTEX_TOKEN: Lazy<Token> = Lazy::new(|| Token::with_policy(GlobalMutex));
+
+#[bench]
+fn contended_with_global_mutex(b: &mut Bencher) {
+    contended(b, |i| {
+        AdaptiveFuture::new(GLOBAL_MUTEX_TOKEN.clone(), move || tiny(i))
+    });
+}
+
+#[bench]
+fn contended_with_adaptive_always_inline(b: &mut Bencher) {
+    contended(b, |i| {
+        AdaptiveFuture::new(Token::always_inline(), move || tiny(i))
+    });
+}
+
+#[bench]
+fn contended_with_nothing(b: &mut Bencher) {
+    contended(b, |i| async move { tiny(i) });
+}
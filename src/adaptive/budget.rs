@@ -0,0 +1,144 @@
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use super::core::now_nanos;
+
+/// The configured budget and window, in nanoseconds. `0` means no budget.
+static BUDGET: AtomicU64 = AtomicU64::new(0);
+static WINDOW: AtomicU64 = AtomicU64::new(0);
+
+/// A limit on how much blocking work is run inline on each thread (for example, each worker
+/// thread of a runtime), to protect the latency of the other tasks it is running. See
+/// [`set_inline_budget`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InlineBudget {
+    /// How much *wall-time* of work can be run inline per `window`
+    pub budget: Duration,
+    /// How often the budget resets
+    pub window: Duration,
+}
+
+impl InlineBudget {
+    /// At most `budget` of inline work per `window`, on each thread.
+    ///
+    /// # Panics
+    /// If `budget` or `window` are zero.
+    pub fn new(budget: Duration, window: Duration) -> Self {
+        assert!(
+            budget > Duration::ZERO,
+            "the inline budget must not be zero"
+        );
+        assert!(
+            window > Duration::ZERO,
+            "the inline budget window must not be zero"
+        );
+        InlineBudget { budget, window }
+    }
+}
+
+/// Configure the [`InlineBudget`] for all `Token`'s, or remove it with `None` (the default).
+///
+/// Even work that is cheaper than the cutoff can add up to long stalls when there is a lot of it.
+/// Once the work an [`AdaptiveFuture`](super::AdaptiveFuture) has run inline on a thread uses up
+/// the budget, adaptive `Token`'s move their work onto a thread, regardless of their state, until
+/// the window resets. [`Token::always_inline`](super::Token::always_inline) is always run inline,
+/// but still uses up the budget.
+pub fn set_inline_budget(budget: Option<InlineBudget>) {
+    let (budget, window) = match budget {
+        Some(InlineBudget { budget, window }) => (
+            (budget.as_nanos() as u64).max(1),
+            (window.as_nanos() as u64).max(1),
+        ),
+        None => (0, 0),
+    };
+    BUDGET.store(budget, Ordering::Relaxed);
+    WINDOW.store(window, Ordering::Relaxed);
+}
+
+/// The [`InlineBudget`] set with [`set_inline_budget`], if there is one.
+pub fn inline_budget() -> Option<InlineBudget> {
+    match (
+        BUDGET.load(Ordering::Relaxed),
+        WINDOW.load(Ordering::Relaxed),
+    ) {
+        (0, _) | (_, 0) => None,
+        (budget, window) => Some(InlineBudget {
+            budget: Duration::from_nanos(budget),
+            window: Duration::from_nanos(window),
+        }),
+    }
+}
+
+/// How much of the budget has been used on a thread, in nanoseconds
+#[derive(Clone, Copy, Debug, Default)]
+struct Usage {
+    window_start: u64,
+    used: u64,
+}
+
+impl Usage {
+    fn exhausted(&self, now: u64, budget: u64, window: u64) -> bool {
+        now.saturating_sub(self.window_start) < window && self.used >= budget
+    }
+
+    fn charge(&mut self, now: u64, elapsed: u64, window: u64) {
+        if now.saturating_sub(self.window_start) >= window {
+            *self = Usage {
+                window_start: now,
+                used: 0,
+            };
+        }
+        self.used = self.used.saturating_add(elapsed);
+    }
+}
+
+thread_local! {
+    static USAGE: Cell<Usage> = Cell::new(Usage::default());
+}
+
+/// Whether the inline budget of the current thread is used up
+pub(crate) fn exhausted() -> bool {
+    match (
+        BUDGET.load(Ordering::Relaxed),
+        WINDOW.load(Ordering::Relaxed),
+    ) {
+        (0, _) | (_, 0) => false,
+        (budget, window) => USAGE.with(|usage| usage.get().exhausted(now_nanos(), budget, window)),
+    }
+}
+
+/// Use up some of the inline budget of the current thread
+pub(crate) fn charge(elapsed: Duration) {
+    let window = WINDOW.load(Ordering::Relaxed);
+    if window == 0 {
+        return;
+    }
+    USAGE.with(|usage| {
+        let mut current = usage.get();
+        current.charge(now_nanos(), elapsed.as_nanos() as u64, window);
+        usage.set(current);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage() {
+        let mut usage = Usage::default();
+        let (budget, window) = (2, 10);
+
+        usage.charge(1, 1, window);
+        assert!(!usage.exhausted(2, budget, window));
+        usage.charge(3, 1, window);
+        assert!(usage.exhausted(4, budget, window));
+        // The window resets
+        assert!(!usage.exhausted(12, budget, window));
+        usage.charge(12, 1, window);
+        assert!(!usage.exhausted(13, budget, window));
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use super::{AdaptiveFuture, Token, BLOCKING_CUTOFF_DURATION};
+
+/// How many round-trips are measured by [`measure_overhead`]
+const SAMPLES: usize = 32;
+
+/// The cutoff is this many times the measured overhead: work that is cheaper than
+/// moving it onto a thread (and back) a couple of times over is cheaper to run inline.
+const OVERHEAD_FACTOR: u32 = 2;
+
+/// The measured overhead and derived cutoff, in nanoseconds. `0` means uncalibrated.
+static OVERHEAD: AtomicU64 = AtomicU64::new(0);
+static CUTOFF: AtomicU64 = AtomicU64::new(0);
+
+/// The result of measuring the overhead of moving work onto a thread, on the current runtime.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Calibration {
+    /// The median round-trip time of moving a no-op onto a thread, and waking up the task
+    /// waiting for it
+    pub overhead: Duration,
+    /// The cutoff derived from `overhead`
+    pub cutoff: Duration,
+}
+
+impl Calibration {
+    fn from_overhead(overhead: Duration) -> Self {
+        Calibration {
+            overhead,
+            cutoff: overhead * OVERHEAD_FACTOR,
+        }
+    }
+}
+
+/// Measure the overhead of moving work onto a thread on the current runtime, without
+/// changing any cutoffs.
+pub async fn measure_overhead() -> Calibration {
+    let mut samples = Vec::with_capacity(SAMPLES);
+    for _ in 0..SAMPLES {
+        let now = Instant::now();
+        AdaptiveFuture::new(Token::always_spawn(), || ()).await;
+        samples.push(now.elapsed());
+    }
+    samples.sort();
+
+    Calibration::from_overhead(samples[SAMPLES / 2])
+}
+
+/// Measure the overhead of moving work onto a thread on the current runtime (see [`measure_overhead`]),
+/// and use the derived cutoff for all [`Token`](super::Token)'s that don't configure their own
+/// with [`TokenBuilder::cutoff`](super::TokenBuilder::cutoff), instead of
+/// [`BLOCKING_CUTOFF_DURATION`](super::BLOCKING_CUTOFF_DURATION).
+///
+/// This is opt-in, and is best called once at startup, after the runtime is
+/// built. The result is returned so it can be logged.
+pub async fn calibrate() -> Calibration {
+    let calibration = measure_overhead().await;
+    install(calibration);
+    calibration
+}
+
+/// [`calibrate`] now, and then every `period`, forever. This is meant to be spawned as its own
+/// task.
+///
+/// This needs a runtime feature, to sleep between calibrations.
+///
+/// # Panics
+/// With `tokio`, if the runtime was built without
+/// [`enable_time`](tokio::runtime::Builder::enable_time), when it first sleeps.
+#[cfg(any(
+    feature = "tokio",
+    feature = "async-std-experimental",
+    feature = "smol"
+))]
+pub async fn calibrate_every(period: Duration) {
+    loop {
+        calibrate().await;
+        sleep(period).await;
+    }
+}
+
+#[cfg(feature = "tokio")]
+async fn sleep(period: Duration) {
+    tokio::time::sleep(period).await
+}
+
+#[cfg(feature = "async-std-experimental")]
+async fn sleep(period: Duration) {
+    async_std::task::sleep(period).await
+}
+
+#[cfg(feature = "smol")]
+async fn sleep(period: Duration) {
+    async_io::Timer::after(period).await;
+}
+
+/// The last result of [`calibrate`], if it has been called.
+pub fn calibration() -> Option<Calibration> {
+    match (
+        OVERHEAD.load(Ordering::Relaxed),
+        CUTOFF.load(Ordering::Relaxed),
+    ) {
+        (0, _) | (_, 0) => None,
+        (overhead, cutoff) => Some(Calibration {
+            overhead: Duration::from_nanos(overhead),
+            cutoff: Duration::from_nanos(cutoff),
+        }),
+    }
+}
+
+fn install(calibration: Calibration) {
+    // Round up so a (very unlikely) 0ns measurement still counts as calibrated
+    let nanos = |d: Duration| (d.as_nanos() as u64).max(1);
+    OVERHEAD.store(nanos(calibration.overhead), Ordering::Relaxed);
+    CUTOFF.store(nanos(calibration.cutoff), Ordering::Relaxed);
+}
+
+/// The cutoff used by `Token`'s that don't configure their own
+pub(crate) fn default_cutoff() -> Duration {
+    match CUTOFF.load(Ordering::Relaxed) {
+        0 => BLOCKING_CUTOFF_DURATION,
+        nanos => Duration::from_nanos(nanos),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_overhead() {
+        let calibration = Calibration::from_overhead(Duration::from_micros(30));
+        assert_eq!(calibration.cutoff, Duration::from_micros(60));
+    }
+}
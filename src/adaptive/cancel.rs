@@ -0,0 +1,29 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Passed to the work of an [`AdaptiveFuture::cancellable`](super::AdaptiveFuture::cancellable),
+/// to check whether the `AdaptiveFuture` has been dropped (for example, because a request timed
+/// out), so long-running work can stop early.
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    /// Whether the `AdaptiveFuture` waiting on this work has been dropped, and its result
+    /// would be thrown away. This is never true for work that runs inline, as the
+    /// `AdaptiveFuture` is running it.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Cancels a `Cancellation` when it is dropped, along with the `AdaptiveFuture` holding it
+#[derive(Debug, Default)]
+pub(crate) struct CancelOnDrop(pub(crate) Cancellation);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        (self.0).0.store(true, Ordering::Relaxed);
+    }
+}
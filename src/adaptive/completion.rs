@@ -0,0 +1,121 @@
+use atomic_waker::AtomicWaker;
+use parking_lot::Mutex;
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+    thread,
+};
+
+use super::error::AdaptiveError;
+
+/// Where spawned work leaves its result (or panic payload) for the `AdaptiveFuture` waiting
+/// on it, which is woken up once it is there.
+pub(crate) struct Completion<O> {
+    result: Mutex<Option<Result<O, AdaptiveError>>>,
+    waker: AtomicWaker,
+}
+
+/// Handed to the spawned work, to complete the `Completion` with. If it is dropped without
+/// completing it (for example, because the runtime dropped the work while shutting down), the
+/// `Completion` is completed with a cancelled `AdaptiveError`, so nobody waits for it forever.
+pub(crate) struct Completer<O>(Option<Arc<Completion<O>>>);
+
+impl<O> Completion<O> {
+    pub(crate) fn new() -> (Arc<Self>, Completer<O>) {
+        let completion = Arc::new(Completion {
+            result: Mutex::new(None),
+            waker: AtomicWaker::new(),
+        });
+        (completion.clone(), Completer(Some(completion)))
+    }
+
+    fn set(&self, result: Result<O, AdaptiveError>) {
+        *self.result.lock() = Some(result);
+        self.waker.wake();
+    }
+
+    /// Take the result, or register to be woken up when it is there
+    pub(crate) fn poll_result(&self, cx: &mut Context<'_>) -> Poll<Result<O, AdaptiveError>> {
+        if let Some(result) = self.result.lock().take() {
+            return Poll::Ready(result);
+        }
+
+        self.waker.register(cx.waker());
+        // The work may have completed before we registered
+        match self.result.lock().take() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<O> Completer<O> {
+    /// Called by the spawned work with its result, or the payload it panicked with
+    pub(crate) fn complete(mut self, result: thread::Result<O>) {
+        if let Some(completion) = self.0.take() {
+            completion.set(result.map_err(AdaptiveError::panicked));
+        }
+    }
+}
+
+impl<O> Drop for Completer<O> {
+    fn drop(&mut self) {
+        if let Some(completion) = self.0.take() {
+            completion.set(Err(AdaptiveError::cancelled()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        task::{Wake, Waker},
+    };
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_completion() {
+        let wakes = Arc::new(CountingWaker::default());
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let (completion, completer) = Completion::new();
+        assert!(completion.poll_result(&mut cx).is_pending());
+
+        thread::spawn(move || completer.complete(Ok(1)))
+            .join()
+            .unwrap();
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        match completion.poll_result(&mut cx) {
+            Poll::Ready(Ok(val)) => assert_eq!(val, 1),
+            _ => panic!("the result should be ready"),
+        }
+    }
+
+    #[test]
+    fn test_completer_dropped() {
+        let wakes = Arc::new(CountingWaker::default());
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let (completion, completer) = Completion::<i32>::new();
+        assert!(completion.poll_result(&mut cx).is_pending());
+
+        drop(completer);
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        match completion.poll_result(&mut cx) {
+            Poll::Ready(Err(e)) => assert!(e.is_cancelled()),
+            _ => panic!("the work should be cancelled"),
+        }
+    }
+}
@@ -4,35 +4,320 @@ use pin_project::pin_project;
 use std::{
     collections::HashMap,
     future::Future,
+    panic::{catch_unwind, AssertUnwindSafe},
     pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
     task::{Context, Poll},
     time::{Duration, Instant},
 };
-// TODO(azw): support more executors
-#[cfg(feature = "async-std-experimental")]
-use async_std::task::{spawn_blocking, JoinHandle};
-#[cfg(feature = "async-std-experimental")]
-use futures::channel::oneshot::{channel, Receiver};
-#[cfg(feature = "tokio")]
-use tokio::{
-    sync::oneshot::{channel, Receiver},
-    task::{spawn_blocking, JoinHandle},
+
+use super::{
+    budget,
+    calibrate::default_cutoff,
+    cancel::{CancelOnDrop, Cancellation},
+    completion::Completion,
+    error::AdaptiveError,
+    policy::{AdaptiveState, LastSample, Outcome, SchedulingPolicy},
+    spawner::{default_spawner, BlockingSpawner},
+    stats::{Stats, TokenStats},
+    token::{Token, TokenType},
 };
 
-use super::token::{Token, TokenType};
+// TODO(guswynn): Do I need seqcst?
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of `TokenState`'s that haven't been dropped
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of adaptive [`Token`](super::Token)'s whose state is currently alive. The state of a
+/// `Token` is shared by all of its clones (and any work it is running), and is freed when the
+/// last of them is dropped.
+///
+/// This is meant for observability, for example to check that creating a `Token` per request
+/// doesn't leak memory.
+pub fn live_tokens() -> usize {
+    LIVE.load(Ordering::Relaxed)
+}
 
-static TIMINGS: Lazy<Mutex<HashMap<Token, AdaptiveState>>> =
+/// Every live `TokenState`, by id. Only locked when creating, dropping, or
+/// collecting statistics for `Token`'s, never by `AdaptiveFuture`.
+static TOKENS: Lazy<Mutex<HashMap<usize, Weak<TokenState>>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
-#[derive(Clone, Copy)]
-enum AdaptiveState {
-    Inline,
-    Spawn,
+pub(crate) fn track(state: &Arc<TokenState>) {
+    TOKENS.lock().insert(state.id, Arc::downgrade(state));
+}
+
+/// A snapshot of the statistics of every live adaptive [`Token`](super::Token), ordered by
+/// when they were created. See [`Token::stats`](super::Token::stats).
+pub fn stats() -> Vec<TokenStats> {
+    // The lock is released before these `Arc`'s are dropped, as dropping the
+    // last one takes the lock.
+    let mut states: Vec<Arc<TokenState>> =
+        TOKENS.lock().values().filter_map(Weak::upgrade).collect();
+    states.sort_by_key(|state| state.id);
+    states.iter().map(|state| state.stats()).collect()
+}
+
+/// The base for `Instant`'s stored in atomics, as nanoseconds since `EPOCH`
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+pub(crate) fn now_nanos() -> u64 {
+    EPOCH.elapsed().as_nanos() as u64
+}
+
+/// How often a `Token` that is spawning its work runs it inline anyways, to re-measure it
+#[derive(Debug, Default)]
+struct Probe {
+    /// `0` if we don't probe after a number of calls
+    every_calls: AtomicU64,
+    /// In nanoseconds, `0` if we don't probe after a period of time
+    every: AtomicU64,
+    /// Whether the policy was spawning work on the last decision
+    spawning: AtomicBool,
+    /// Calls spawned since the last probe
+    calls: AtomicU64,
+    /// When we last probed, or started spawning, see `now_nanos`
+    since: AtomicU64,
 }
 
-impl Default for AdaptiveState {
+impl Probe {
+    fn reset(&self, now: u64) {
+        self.calls.store(0, Ordering::Relaxed);
+        self.since.store(now, Ordering::Relaxed);
+    }
+
+    /// Called for every decision made by the policy, possibly replacing `Spawn`
+    /// with a probing `Inline`
+    fn decide(&self, wanted: AdaptiveState) -> AdaptiveState {
+        if wanted != AdaptiveState::Spawn {
+            // Avoid writing to the shared cache-line if we can
+            if self.spawning.load(Ordering::Relaxed) {
+                self.spawning.store(false, Ordering::Relaxed);
+            }
+            return wanted;
+        }
+
+        let every_calls = self.every_calls.load(Ordering::Relaxed);
+        let every = self.every.load(Ordering::Relaxed);
+        if every_calls == 0 && every == 0 {
+            return wanted;
+        }
+
+        let now = now_nanos();
+        if !self.spawning.swap(true, Ordering::Relaxed) {
+            self.reset(now);
+        }
+
+        // Only one of the concurrent decisions that make a probe due actually probes
+        let calls_due =
+            every_calls != 0 && self.calls.fetch_add(1, Ordering::Relaxed) + 1 == every_calls;
+        let time_due = every != 0 && {
+            let since = self.since.load(Ordering::Relaxed);
+            now.saturating_sub(since) >= every
+                && self
+                    .since
+                    .compare_exchange(since, now, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+        };
+
+        if calls_due || time_due {
+            self.reset(now);
+            AdaptiveState::Inline
+        } else {
+            AdaptiveState::Spawn
+        }
+    }
+}
+
+/// The weight of each new sample in the `CostModel`'s moving average
+const COST_MODEL_ALPHA: f64 = 0.25;
+
+/// A `Token`'s learned cost of work per unit of its cost hint, see
+/// `AdaptiveFuture::with_cost_hint`
+#[derive(Debug)]
+struct CostModel {
+    /// The bits of an `f64` moving average of nanoseconds per unit. `NaN` before the
+    /// first sample.
+    nanos_per_unit: AtomicU64,
+}
+
+impl Default for CostModel {
     fn default() -> Self {
-        AdaptiveState::Inline
+        CostModel {
+            nanos_per_unit: AtomicU64::new(f64::NAN.to_bits()),
+        }
+    }
+}
+
+impl CostModel {
+    /// Predict the *wall-time* of work of `size` units, if we have learned anything yet
+    fn predict(&self, size: usize) -> Option<Duration> {
+        let per_unit = f64::from_bits(self.nanos_per_unit.load(Ordering::Relaxed));
+        if per_unit.is_nan() {
+            return None;
+        }
+        Some(Duration::from_nanos((per_unit * size as f64) as u64))
+    }
+
+    fn observe(&self, elapsed: Duration, size: usize) {
+        if size == 0 {
+            return;
+        }
+        let sample = elapsed.as_nanos() as f64 / size as f64;
+        let _ = self
+            .nanos_per_unit
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let avg = f64::from_bits(bits);
+                Some(if avg.is_nan() {
+                    sample.to_bits()
+                } else {
+                    (COST_MODEL_ALPHA * sample + (1.0 - COST_MODEL_ALPHA) * avg).to_bits()
+                })
+            });
+    }
+}
+
+/// The state of an adaptive `Token`, shared by all of its copies. Everything used by
+/// `AdaptiveFuture` is lock-free, so many threads can share a `Token` without contending
+/// on anything but cache-lines.
+pub(crate) struct TokenState {
+    id: usize,
+    /// Shared with the labels of metrics
+    name: Option<Arc<str>>,
+    policy: Box<dyn SchedulingPolicy>,
+    /// `None` uses the default cutoff, which may be calibrated
+    cutoff: Option<Duration>,
+    /// Where to run work until the policy has observed any
+    initial_state: Option<AdaptiveState>,
+    observed: AtomicBool,
+    probe: Probe,
+    cost_model: CostModel,
+    stats: Stats,
+    spawner: Option<Box<dyn BlockingSpawner>>,
+}
+
+impl Default for TokenState {
+    fn default() -> Self {
+        TokenState::new(None, Box::new(LastSample::default()), None, None)
+    }
+}
+
+impl TokenState {
+    pub(crate) fn new(
+        name: Option<Arc<str>>,
+        policy: Box<dyn SchedulingPolicy>,
+        cutoff: Option<Duration>,
+        initial_state: Option<AdaptiveState>,
+    ) -> Self {
+        LIVE.fetch_add(1, Ordering::Relaxed);
+        TokenState {
+            id: CURRENT.fetch_add(1, Ordering::SeqCst),
+            name,
+            policy,
+            cutoff,
+            initial_state,
+            observed: AtomicBool::new(false),
+            probe: Probe::default(),
+            cost_model: CostModel::default(),
+            stats: Stats::new(initial_state.unwrap_or(AdaptiveState::Inline)),
+            spawner: None,
+        }
+    }
+
+    /// Move work onto a thread with `spawner`, instead of the default one
+    pub(crate) fn use_spawner(&mut self, spawner: Box<dyn BlockingSpawner>) {
+        self.spawner = Some(spawner);
+    }
+
+    pub(crate) fn id(&self) -> usize {
+        self.id
+    }
+
+    pub(crate) fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub(crate) fn probe_every_calls(&self, calls: u64) {
+        self.probe.every_calls.store(calls, Ordering::Relaxed);
+    }
+
+    pub(crate) fn probe_every(&self, every: Duration) {
+        // Round up, as `0` disables probing
+        let every = (every.as_nanos() as u64).max(1);
+        self.probe.since.store(now_nanos(), Ordering::Relaxed);
+        self.probe.every.store(every, Ordering::Relaxed);
+    }
+
+    fn cutoff(&self) -> Duration {
+        self.cutoff.unwrap_or_else(default_cutoff)
+    }
+
+    /// Decide where to run the next piece of work. Work with a cost hint is placed based on
+    /// its predicted *wall-time*, once we can predict it.
+    fn decide(&self, cost_hint: Option<usize>) -> AdaptiveState {
+        if let Some(predicted) = cost_hint.and_then(|size| self.cost_model.predict(size)) {
+            return if predicted > self.cutoff() {
+                AdaptiveState::Spawn
+            } else {
+                AdaptiveState::Inline
+            };
+        }
+
+        let wanted = self.policy_state();
+        if self.stats.record_placement(wanted) {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(
+                token.id = self.id,
+                token.name = self.name(),
+                state = ?wanted,
+                "token switched where it runs work"
+            );
+        }
+        self.probe.decide(wanted)
+    }
+
+    /// Where the policy wants to run the next piece of work
+    fn policy_state(&self) -> AdaptiveState {
+        match self.initial_state {
+            Some(initial) if !self.observed.load(Ordering::Relaxed) => initial,
+            _ => self.policy.decide(),
+        }
+    }
+
+    /// Never calls the policy, as deciding may change its state
+    pub(crate) fn stats(&self) -> TokenStats {
+        self.stats.snapshot(self.id, self.name())
+    }
+
+    fn skipped(&self) {
+        self.stats.record_skipped();
+    }
+
+    fn observe(&self, elapsed: Duration, placement: AdaptiveState, cost_hint: Option<usize>) {
+        self.stats.record_run(elapsed, placement);
+        if let Some(size) = cost_hint {
+            self.cost_model.observe(elapsed, size);
+        }
+        self.policy.observe(&Outcome {
+            elapsed,
+            placement,
+            cutoff: self.cutoff(),
+            cost_hint,
+        });
+        if !self.observed.load(Ordering::Relaxed) {
+            self.observed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for TokenState {
+    fn drop(&mut self) {
+        LIVE.fetch_sub(1, Ordering::Relaxed);
+        TOKENS.lock().remove(&self.id);
     }
 }
 
@@ -40,37 +325,125 @@ impl Default for AdaptiveState {
 pub struct TimedBlockingFuture<O, F> {
     fut: Option<F>,
     token: Token,
-    cutoff: Duration,
-    inner: Option<JoinHandle<O>>,
-    wakeup: Option<Receiver<()>>,
+    cost_hint: Option<usize>,
+    completion: Option<Arc<Completion<O>>>,
+    /// Cancelled when we are dropped
+    cancel: Option<CancelOnDrop>,
 }
 
 impl<O, F: FnOnce() -> O> TimedBlockingFuture<O, F> {
-    pub fn new(token: Token, cutoff: Duration, future: F) -> Self {
+    pub fn new(
+        token: Token,
+        cost_hint: Option<usize>,
+        cancellation: Option<Cancellation>,
+        future: F,
+    ) -> Self {
         TimedBlockingFuture {
             fut: Some(future),
-            cutoff,
             token,
-            inner: None,
-            wakeup: None,
+            cost_hint,
+            completion: None,
+            cancel: cancellation.map(CancelOnDrop),
         }
     }
 }
 
-fn track_and_run<O, F: FnOnce() -> O>(token: Token, cutoff: Duration, f: F) -> O {
+/// The `token` label of metrics: the name of the `Token`, or `unnamed` if it doesn't have one.
+/// Unnamed `Token`'s may be created per request, and recorders keep every label value forever.
+#[cfg(feature = "metrics")]
+fn metrics_token_label(token: &Token) -> metrics::SharedString {
+    match &token.0 {
+        TokenType::Adaptive(state) => match &state.name {
+            Some(name) => metrics::SharedString::from_shared(name.clone()),
+            None => metrics::SharedString::const_str("unnamed"),
+        },
+        TokenType::AlwaysInline => metrics::SharedString::const_str("always_inline"),
+        TokenType::AlwaysSpawn(_) => metrics::SharedString::const_str("always_spawn"),
+        TokenType::AlwaysBlockInPlace => metrics::SharedString::const_str("always_block_in_place"),
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn metrics_placement_label(placement: AdaptiveState) -> &'static str {
+    match placement {
+        AdaptiveState::Inline => "inline",
+        AdaptiveState::Spawn => "spawn",
+        AdaptiveState::BlockInPlace => "block_in_place",
+    }
+}
+
+/// Whether `block_in_place` can be used here, which panics outside of
+/// multi-threaded runtimes
+#[cfg(feature = "tokio")]
+fn can_block_in_place() -> bool {
+    matches!(
+        tokio::runtime::Handle::try_current().map(|handle| handle.runtime_flavor()),
+        Ok(tokio::runtime::RuntimeFlavor::MultiThread)
+    )
+}
+
+#[cfg(not(feature = "tokio"))]
+fn can_block_in_place() -> bool {
+    false
+}
+
+#[cfg(feature = "tokio")]
+use tokio::task::block_in_place;
+
+/// Never called, as `can_block_in_place` is always false
+#[cfg(not(feature = "tokio"))]
+fn block_in_place<O, F: FnOnce() -> O>(f: F) -> O {
+    f()
+}
+
+/// Record that spawned work was skipped, as its `AdaptiveFuture` was dropped before it started
+fn record_skipped(token: &Token) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(?token, "skipped work whose AdaptiveFuture was dropped");
+
+    #[cfg(feature = "metrics")]
+    metrics::counter!("impedance_skipped_total", "token" => metrics_token_label(token))
+        .increment(1);
+
+    if let TokenType::Adaptive(state) = &token.0 {
+        state.skipped();
+    }
+}
+
+fn track_and_run<O, F: FnOnce() -> O>(
+    token: &Token,
+    placement: AdaptiveState,
+    cost_hint: Option<usize>,
+    f: F,
+) -> O {
     let now = Instant::now();
     let ret = f();
+    let elapsed = now.elapsed();
+    if placement == AdaptiveState::Inline {
+        budget::charge(elapsed);
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::trace!(?elapsed, ?placement, "ran blocking work");
+
+    #[cfg(feature = "metrics")]
+    {
+        let token = metrics_token_label(token);
+        let placement = metrics_placement_label(placement);
+        metrics::counter!("impedance_runs_total", "token" => token.clone(), "placement" => placement)
+            .increment(1);
+        metrics::histogram!("impedance_run_duration_seconds", "token" => token, "placement" => placement)
+            .record(elapsed);
+    }
 
-    if now.elapsed() > cutoff {
-        TIMINGS.lock().insert(token, AdaptiveState::Spawn);
-    } else {
-        TIMINGS.lock().insert(token, AdaptiveState::Inline);
+    if let TokenType::Adaptive(state) = &token.0 {
+        state.observe(elapsed, placement, cost_hint);
     }
     ret
 }
 
 impl<O: Send + 'static, F: FnOnce() -> O + Send + 'static> Future for TimedBlockingFuture<O, F> {
-    type Output = O;
+    type Output = Result<O, AdaptiveError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
@@ -78,117 +451,205 @@ impl<O: Send + 'static, F: FnOnce() -> O + Send + 'static> Future for TimedBlock
         loop {
             match this.fut.take() {
                 Some(f) => {
-                    let state = match this.token.0 {
+                    let placement = match &this.token.0 {
                         TokenType::AlwaysInline => AdaptiveState::Inline,
-                        TokenType::AlwaysSpawn => AdaptiveState::Spawn,
-                        // Need to drop the lock before entering the `track_and_run` section
-                        _ => *(*TIMINGS.lock()).entry(*this.token).or_default(),
+                        TokenType::AlwaysSpawn(_) => AdaptiveState::Spawn,
+                        TokenType::AlwaysBlockInPlace => AdaptiveState::BlockInPlace,
+                        TokenType::Adaptive(state) => match state.decide(*this.cost_hint) {
+                            AdaptiveState::Inline if budget::exhausted() => AdaptiveState::Spawn,
+                            placement => placement,
+                        },
                     };
+                    let placement = match placement {
+                        AdaptiveState::BlockInPlace if !can_block_in_place() => {
+                            AdaptiveState::Spawn
+                        }
+                        placement => placement,
+                    };
+
+                    // A child of the caller's current span, that the work runs in,
+                    // wherever it runs
+                    #[cfg(feature = "tracing")]
+                    let span = tracing::debug_span!(
+                        "adaptive_future",
+                        token = ?this.token,
+                        placement = ?placement,
+                    );
 
-                    match state {
+                    match placement {
                         AdaptiveState::Inline => {
+                            #[cfg(feature = "tracing")]
+                            let _enter = span.enter();
                             // Just run it inline
-                            return Poll::Ready(track_and_run(*this.token, *this.cutoff, f));
+                            return Poll::Ready(Ok(track_and_run(
+                                this.token,
+                                placement,
+                                *this.cost_hint,
+                                f,
+                            )));
+                        }
+                        AdaptiveState::BlockInPlace => {
+                            #[cfg(feature = "tracing")]
+                            let _enter = span.enter();
+                            // Run it on this thread, after handing off the other tasks of this
+                            // worker
+                            let (token, cost_hint) = (&*this.token, *this.cost_hint);
+                            return Poll::Ready(Ok(block_in_place(|| {
+                                track_and_run(token, placement, cost_hint, f)
+                            })));
                         }
                         AdaptiveState::Spawn => {
                             // Spawn the blocking task
-                            let (tx, rx) = channel();
-                            let jh = {
-                                let token = *this.token;
-                                let cutoff = *this.cutoff;
-                                spawn_blocking(move || {
-                                    let ret = track_and_run(token, cutoff, f);
-                                    // Panic's cause tx to be dropped which will wake the
-                                    // Reciever
-                                    let _ = tx.send(());
-                                    ret
-                                })
+                            let token = this.token.clone();
+                            let cost_hint = *this.cost_hint;
+                            #[cfg(any(feature = "tracing", feature = "metrics"))]
+                            let queued = Instant::now();
+                            let run = move || {
+                                #[cfg(feature = "tracing")]
+                                let _enter = span.enter();
+                                #[cfg(feature = "tracing")]
+                                tracing::trace!(queue_wait = ?queued.elapsed(), "started spawned work");
+                                #[cfg(feature = "metrics")]
+                                metrics::histogram!(
+                                    "impedance_queue_latency_seconds",
+                                    "token" => metrics_token_label(&token)
+                                )
+                                .record(queued.elapsed());
+
+                                track_and_run(&token, placement, cost_hint, f)
+                            };
+
+                            let spawner: &dyn BlockingSpawner = match &this.token.0 {
+                                TokenType::Adaptive(state) => match &state.spawner {
+                                    Some(spawner) => &**spawner,
+                                    None => default_spawner(),
+                                },
+                                TokenType::AlwaysSpawn(Some(spawner)) => &**spawner,
+                                _ => default_spawner(),
                             };
 
-                            // Store the reciever to poll later
-                            *this.wakeup = Some(rx);
-                            // Store the handle to the spawned blocking task, to join later
-                            *this.inner = Some(jh);
-
-                            // TODO(guswynn): This is hacky, I should just make a waker myself,
-                            // but making sure I wake when tx is dropped is non-trivial
-                            //
-                            // TODO(guswynn): figure out how to control this in cfg(test):
-                            // std::thread::sleep(Duration::from_secs(1));
-                            //
-                            // Polling the Reciever registers the task's waker
-                            // If the blocking thread is working fast, we may immediately
-                            // see that we are ready. We aren't just wrapping around as an
-                            // optimization: We must read the value off the JoinHandle
-                            // as we no longer have any way to recieve wakeups.
-                            match Pin::new(this.wakeup.as_mut().unwrap()).poll(cx) {
-                                Poll::Ready(_) => {
-                                    *this.wakeup = None;
-                                    continue;
+                            // Skip the work if we are dropped before a thread picks it up,
+                            // as nobody is waiting for it anymore
+                            let cancellation = this
+                                .cancel
+                                .get_or_insert_with(CancelOnDrop::default)
+                                .0
+                                .clone();
+                            let skipped = this.token.clone();
+
+                            // If the work is dropped without running (for example, because
+                            // the runtime is shutting down), the `Completer` is dropped with
+                            // it, and we are woken up with a cancelled `AdaptiveError`
+                            let (completion, completer) = Completion::new();
+                            spawner.spawn(Box::new(move || {
+                                if cancellation.is_cancelled() {
+                                    record_skipped(&skipped);
+                                    return;
                                 }
-                                Poll::Pending => {}
-                            }
+                                // Catch panics ourselves, so every spawner propagates their
+                                // payload
+                                completer.complete(catch_unwind(AssertUnwindSafe(run)));
+                            }));
+                            *this.completion = Some(completion);
 
-                            return Poll::Pending;
+                            // Poll the result, which registers our waker. If the thread is
+                            // working fast, we may immediately see that we are ready.
+                            continue;
                         }
                     }
                 }
                 None => {
-                    let jh = this.inner.as_mut().expect("re-polled a Ready Future");
-
-                    // Re-register the waker if its still possible
-                    // If we got a ready, we need to busy poll so we can get join handle
-                    // value signalled by this channel being ready. We MUST busy poll;
-                    // its not an optimization: once we consume our last possible wakeup,
-                    // we need to get the value out of the join handle
-                    //
-                    // TODO(guswynn): catch_unwind in the closure yourself and send the value
-                    // from the JoinHandle?
-                    let busy_poll = match this.wakeup.as_mut() {
-                        Some(rx) => match Pin::new(rx).poll(cx) {
-                            Poll::Ready(_) => {
-                                // Like above, we don't care about the value,
-                                // as joining the handle below is what we want.
-                                *this.wakeup = None;
-                                true
-                            }
-                            Poll::Pending => {
-                                // Explicit false, as our waker it correctly registered in the
-                                // Reciever
-                                false
-                            }
-                        },
-                        None => {
-                            // an unset reciever means we need to continue to loop
-                            // until the join handle gives us the value
-                            true
-                        }
-                    };
-
-                    match Pin::new(jh).poll(cx) {
-                        #[cfg(feature = "async-std-experimental")]
-                        Poll::Ready(val) => return Poll::Ready(val),
-                        #[cfg(feature = "tokio")]
-                        Poll::Ready(Ok(val)) => return Poll::Ready(val),
-                        #[cfg(feature = "tokio")]
-                        Poll::Ready(Err(e)) => match e.try_into_panic() {
-                            Ok(panic) => {
-                                std::panic::resume_unwind(panic);
-                            }
-                            Err(_) => {
-                                // Task is shutdown so we just pend:
-                                // We never abort the sub-task ourselves, so something
-                                // else is shutting everything else down, and the task
-                                // polling us will likely be shutting down as well
-                                // TODO(guswynn): figure out a way to test this
-                                return Poll::Pending;
-                            }
-                        },
-                        Poll::Pending if busy_poll => continue,
-                        Poll::Pending => return Poll::Pending,
+                    let completion = this.completion.as_ref().expect("re-polled a Ready Future");
+                    let result = completion.poll_result(cx);
+                    if result.is_ready() {
+                        // So polling us again hits the `expect` above
+                        *this.completion = None;
                     }
+                    return result;
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawning() -> TokenState {
+        let state = TokenState::default();
+        state.observe(Duration::from_millis(1), AdaptiveState::Inline, None);
+        state
+    }
+
+    #[test]
+    fn test_probe_every_calls() {
+        let state = spawning();
+        state.probe_every_calls(3);
+
+        assert_eq!(state.decide(None), AdaptiveState::Spawn);
+        assert_eq!(state.decide(None), AdaptiveState::Spawn);
+        assert_eq!(state.decide(None), AdaptiveState::Inline);
+        // A probe that is still slow keeps us spawning
+        state.observe(Duration::from_millis(1), AdaptiveState::Inline, None);
+        assert_eq!(state.decide(None), AdaptiveState::Spawn);
+        assert_eq!(state.decide(None), AdaptiveState::Spawn);
+        assert_eq!(state.decide(None), AdaptiveState::Inline);
+        // A fast probe brings us back inline
+        state.observe(Duration::from_micros(10), AdaptiveState::Inline, None);
+        assert_eq!(state.decide(None), AdaptiveState::Inline);
+    }
+
+    #[test]
+    fn test_probe_every() {
+        let state = spawning();
+        state.probe_every(Duration::from_millis(10));
+
+        assert_eq!(state.decide(None), AdaptiveState::Spawn);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(state.decide(None), AdaptiveState::Inline);
+        assert_eq!(state.decide(None), AdaptiveState::Spawn);
+    }
+
+    #[test]
+    fn test_initial_state() {
+        let state = TokenState::new(
+            None,
+            Box::new(LastSample::default()),
+            None,
+            Some(AdaptiveState::Spawn),
+        );
+        assert_eq!(state.decide(None), AdaptiveState::Spawn);
+        assert_eq!(state.decide(None), AdaptiveState::Spawn);
+        state.observe(Duration::from_micros(10), AdaptiveState::Spawn, None);
+        assert_eq!(state.decide(None), AdaptiveState::Inline);
+    }
+
+    #[test]
+    fn test_cost_hint() {
+        let state = TokenState::default();
+        // Nothing is known about the cost yet
+        assert_eq!(state.decide(Some(1000)), AdaptiveState::Inline);
+
+        // 1us per unit
+        state.observe(Duration::from_millis(1), AdaptiveState::Inline, Some(1000));
+        assert_eq!(state.decide(Some(10)), AdaptiveState::Inline);
+        assert_eq!(state.decide(Some(1000)), AdaptiveState::Spawn);
+        // Work without a hint falls back to the policy
+        assert_eq!(state.decide(None), AdaptiveState::Spawn);
+
+        // Small work with a hint teaches the model, but doesn't change it much
+        state.observe(Duration::from_micros(20), AdaptiveState::Inline, Some(10));
+        assert_eq!(state.decide(Some(10)), AdaptiveState::Inline);
+        assert_eq!(state.decide(Some(1000)), AdaptiveState::Spawn);
+        assert_eq!(state.decide(None), AdaptiveState::Inline);
+    }
+
+    #[test]
+    fn test_no_probe() {
+        let state = spawning();
+        for _ in 0..100 {
+            assert_eq!(state.decide(None), AdaptiveState::Spawn);
+        }
+    }
+}
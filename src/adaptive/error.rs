@@ -0,0 +1,127 @@
+use parking_lot::Mutex;
+use std::{any::Any, fmt};
+
+/// Why an [`AdaptiveFuture::try_new`](super::AdaptiveFuture::try_new) didn't produce the
+/// result of its work: it either panicked (see [`is_panic`](AdaptiveError::is_panic)), or was
+/// dropped before it finished (see [`is_cancelled`](AdaptiveError::is_cancelled)).
+///
+/// `AdaptiveError` is `Send` and `Sync`, so it can be converted into other error types, like
+/// `Box<dyn Error + Send + Sync>`.
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+pub struct AdaptiveError(Repr);
+
+#[derive(Debug, thiserror::Error)]
+enum Repr {
+    #[error("the blocking work panicked{}", .0.message())]
+    Panicked(Payload),
+    #[error("the blocking work was dropped before it finished")]
+    Cancelled,
+}
+
+/// A panic payload, which is only `Send`. The lock makes it `Sync`, like the `SyncWrapper` of
+/// tokio's `JoinError`.
+struct Payload(Mutex<Box<dyn Any + Send + 'static>>);
+
+impl Payload {
+    /// The message of the payload, if it is a string like those of `panic!`
+    fn message(&self) -> String {
+        let payload = self.0.lock();
+        if let Some(message) = payload.downcast_ref::<&str>() {
+            format!(": {}", message)
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            format!(": {}", message)
+        } else {
+            String::new()
+        }
+    }
+}
+
+impl fmt::Debug for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Any { .. }")
+    }
+}
+
+impl AdaptiveError {
+    pub(crate) fn panicked(payload: Box<dyn Any + Send + 'static>) -> Self {
+        AdaptiveError(Repr::Panicked(Payload(Mutex::new(payload))))
+    }
+
+    pub(crate) fn cancelled() -> Self {
+        AdaptiveError(Repr::Cancelled)
+    }
+
+    /// Whether the work panicked
+    pub fn is_panic(&self) -> bool {
+        matches!(self.0, Repr::Panicked(_))
+    }
+
+    /// Whether the work was dropped before it finished, for example because the runtime shut
+    /// down before a thread picked it up
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self.0, Repr::Cancelled)
+    }
+
+    /// The payload the work panicked with, which can be re-thrown with
+    /// [`resume_unwind`](std::panic::resume_unwind).
+    ///
+    /// # Panics
+    /// If the work didn't panic, see [`try_into_panic`](AdaptiveError::try_into_panic).
+    pub fn into_panic(self) -> Box<dyn Any + Send + 'static> {
+        self.try_into_panic()
+            .expect("`AdaptiveError` is not a panic")
+    }
+
+    /// The payload the work panicked with, or this error if it didn't panic
+    pub fn try_into_panic(self) -> Result<Box<dyn Any + Send + 'static>, AdaptiveError> {
+        match self.0 {
+            Repr::Panicked(payload) => Ok(payload.0.into_inner()),
+            repr => Err(AdaptiveError(repr)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<AdaptiveError>();
+
+        let boxed: Box<dyn std::error::Error + Send + Sync> = Box::new(AdaptiveError::cancelled());
+        assert_eq!(
+            boxed.to_string(),
+            "the blocking work was dropped before it finished"
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            AdaptiveError::panicked(Box::new("gus")).to_string(),
+            "the blocking work panicked: gus"
+        );
+        assert_eq!(
+            AdaptiveError::panicked(Box::new(format!("gus {}", 1))).to_string(),
+            "the blocking work panicked: gus 1"
+        );
+        assert_eq!(
+            AdaptiveError::panicked(Box::new(42_usize)).to_string(),
+            "the blocking work panicked"
+        );
+    }
+
+    #[test]
+    fn test_into_panic() {
+        let error = AdaptiveError::panicked(Box::new(42_usize));
+        assert!(error.is_panic());
+        assert_eq!(error.into_panic().downcast_ref::<usize>(), Some(&42));
+
+        let error = AdaptiveError::cancelled();
+        assert!(error.is_cancelled());
+        assert!(error.try_into_panic().unwrap_err().is_cancelled());
+    }
+}
@@ -29,10 +29,15 @@
 //!
 //! async fn make_request() -> i32 {
 //!     let response = send_request().await;
-//!     AdaptiveFuture::new(*REQUEST_TOKEN, move || deserialize(&response)).await
+//!     AdaptiveFuture::new(REQUEST_TOKEN.clone(), move || deserialize(&response)).await
 //! }
 //! ```
 //!
+//! [`Token::named`](Token::named) can be used to share a `Token` by name, which also makes it
+//! easier to identify in logs and statistics. It looks the `Token` up in a global registry,
+//! so it is best stored in a `static` like above, instead of being looked up for every piece of
+//! work.
+//!
 //! ## Scheduling Scheme
 //! `AdaptiveFuture` decides when to inline work based on the last *wall-time* of the work
 //! it has performed. The granularity of this *wall-time* is based on the [`Token`](Token) passed
@@ -40,53 +45,220 @@
 //! knowledge of how the *possibly-expensvie* cpu work they are guarding with an `AdaptiveFuture`
 //! will perform, in different parts of their program.
 //!
+//! For noisy work, [`Token::ewma`](Token::ewma) instead decides based on an
+//! exponentially-weighted moving average of the *wall-times*, so a single outlier doesn't
+//! flip the scheduling of all the work that follows it. Work that takes roughly the cutoff
+//! time can use [`Token::hysteresis`](Token::hysteresis), which has separate cutoffs for moving
+//! onto a thread and back inline. When the *tail* of the *wall-times* matters more than
+//! the common case, [`Token::quantile`](Token::quantile) decides based on a quantile (like the
+//! p99) of recent *wall-times*.
+//!
+//! Once a `Token` moves its work onto a thread, it only moves back inline if that work is
+//! measured to be fast *on that thread*. [`Token::probe_every_calls`](Token::probe_every_calls)
+//! and [`Token::probe_every`](Token::probe_every) periodically run the work inline instead, to
+//! re-measure it.
+//!
+//! When the cost of the work depends on its input, like the size of a payload,
+//! [`AdaptiveFuture::with_cost_hint`](AdaptiveFuture::with_cost_hint) lets a `Token` learn and
+//! predict the cost of each piece of work.
+//!
+//! All of these are implementations of [`SchedulingPolicy`](SchedulingPolicy), which you
+//! can implement yourself and use with [`Token::with_policy`](Token::with_policy).
 //!
 //! To see more information about how to construct `Token`'s and various options, see
-//! [`Token`](Token).
+//! [`Token`](Token) and [`TokenBuilder`](TokenBuilder).
 //! The above example shows the common-case default of using a
 //! `static` *unique* `Token` configured to use the default cutoff time ([`BLOCKING_CUTOFF_DURATION`][BLOCKING_CUTOFF_DURATION])
 //!
+//! ## Observability
+//! [`Token::stats`](Token::stats) and [`stats`](stats) return snapshots of what `Token`'s
+//! have been doing: how much work they have run inline and on threads, how long it took, and
+//! how often they have switched between the two. [`render_openmetrics`](render_openmetrics)
+//! renders them for Prometheus.
+//!
+//! ## Inline budget
+//! Even work that is cheaper than the cutoff can add up to long stalls when there is a lot of
+//! it. [`set_inline_budget`](set_inline_budget) limits how much work is run inline on each
+//! thread per window of time, moving work onto a thread once it is used up.
+//!
+//! ## Calibration
+//! The default cutoff was measured on a specific machine, and the real cost of moving work
+//! onto a thread depends on yours (and your runtime). [`calibrate`](calibrate) measures it,
+//! and replaces the default cutoff with one derived from it:
+//!
+//! ```
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let calibration = impedance::adaptive::calibrate().await;
+//! println!("moving work onto a thread costs {:?}", calibration.overhead);
+//! # }
+//! ```
+//!
 //! More complex scheduling schemes may be available in the future.
 use pin_project::pin_project;
 use std::{
     future::Future,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
     pin::Pin,
     task::{Context, Poll},
     time::Duration,
 };
 
 mod token;
-pub use token::Token;
+pub use token::{Token, TokenBuilder};
+mod calibrate;
+#[cfg(any(
+    feature = "tokio",
+    feature = "async-std-experimental",
+    feature = "smol"
+))]
+pub use calibrate::calibrate_every;
+pub use calibrate::{calibrate, calibration, measure_overhead, Calibration};
 mod core;
+mod policy;
+pub use policy::{
+    AdaptiveState, Ewma, Hysteresis, LastSample, Outcome, Quantile, SchedulingPolicy,
+};
+mod budget;
+mod cancel;
+pub use cancel::Cancellation;
+mod completion;
+mod error;
+pub use budget::{inline_budget, set_inline_budget, InlineBudget};
+pub use error::AdaptiveError;
+mod openmetrics;
+mod sketch;
+pub use openmetrics::{render_openmetrics, OPENMETRICS_CONTENT_TYPE};
+mod spawner;
+#[cfg(feature = "async-std-experimental")]
+pub use spawner::AsyncStdSpawner;
+#[cfg(feature = "smol")]
+pub use spawner::SmolSpawner;
+#[cfg(feature = "tokio")]
+pub use spawner::TokioSpawner;
+pub use spawner::{BlockingSpawner, BlockingWork, ThreadSpawner};
+mod stats;
 use self::core::TimedBlockingFuture;
+pub use self::core::{live_tokens, stats};
+pub use stats::{TokenStats, DURATION_BUCKETS};
 
 /// see [here](https://github.com/guswynn/impedance/blob/main/benches/comparisons.rs#L66-L71`)
 /// to get a baseline cost of [spawn_blocking](tokio::task::spawn_blocking) (on your machine)
 ///
-/// Currently this is set to `100_000` nanoseconds. This may change, or need to be made
-/// configurable for your usecase.
+/// Currently this is set to `100_000` nanoseconds. This may change, and can be configured
+/// per-[`Token`](Token) with [`TokenBuilder::cutoff`](TokenBuilder::cutoff), or replaced with a
+/// cutoff measured on your machine with [`calibrate`](calibrate).
 pub const BLOCKING_CUTOFF_DURATION: Duration = Duration::from_nanos(100000);
 
 /// A [`Future`][Future] representing *blocking work*
 ///
 /// It either
 /// 1. Runs work inline in its [`poll`](std::future::Future::poll) implementation
-/// 2. Schedules the work on another thread using [`spawn_blocking`](tokio::task::spawn_blocking)
+/// 2. Schedules the work on another thread using the [`BlockingSpawner`](BlockingSpawner) of its
+///    [`Token`](Token) ([`spawn_blocking`](tokio::task::spawn_blocking) by default, or a rayon pool
+///    with `TokenBuilder::rayon`)
+///
+/// If it is dropped before a thread picks up its work, the work is skipped.
+///
+/// If the work panics, the panic is propagated to the task awaiting this. If the work is dropped
+/// before it finishes (for example, because the runtime shuts down before a thread picks it up),
+/// this never completes. [`AdaptiveFuture::try_new`](AdaptiveFuture::try_new) returns an
+/// [`AdaptiveError`](AdaptiveError) in both cases instead.
 ///
 /// see *[the module documentation](self)* for usage examples.
 #[pin_project]
 pub struct AdaptiveFuture<O, F> {
     #[pin]
     inner: TimedBlockingFuture<O, F>,
+    /// The work was dropped before it finished, so we pend forever
+    cancelled: bool,
 }
 
 impl<O, F: FnOnce() -> O> AdaptiveFuture<O, F> {
     /// Create a new `AdaptiveFuture` that will adaptively schedule blocking work
-    /// inline or in thread ([`spawn_blocking`](tokio::task::spawn_blocking)) associated
+    /// inline or in thread (with the [`BlockingSpawner`](BlockingSpawner) of the `Token`) associated
     /// the [`Token`](Token)
     pub fn new(token: Token, future: F) -> Self {
         AdaptiveFuture {
-            inner: TimedBlockingFuture::new(token, BLOCKING_CUTOFF_DURATION, future),
+            inner: TimedBlockingFuture::new(token, None, None, future),
+            cancelled: false,
+        }
+    }
+
+    /// Create a new `AdaptiveFuture` for work whose cost scales with `size` (for example,
+    /// the length of a payload to deserialize).
+    ///
+    /// The [`Token`](Token) learns the cost of its work per unit of `size`, and once it has,
+    /// uses it to predict whether *this* piece of work will take longer than its cutoff,
+    /// instead of its [`SchedulingPolicy`](SchedulingPolicy). This means a large payload can be
+    /// moved onto a thread even if the `Token`'s recent work was all small, and vice versa.
+    pub fn with_cost_hint(token: Token, size: usize, future: F) -> Self {
+        AdaptiveFuture {
+            inner: TimedBlockingFuture::new(token, Some(size), None, future),
+            cancelled: false,
+        }
+    }
+
+    /// Create a new [`TryAdaptiveFuture`](TryAdaptiveFuture), which schedules its work like
+    /// an `AdaptiveFuture`, but resolves to an [`AdaptiveError`](AdaptiveError) if the work
+    /// panics (wherever it ran), or is dropped before it finishes, for example because the
+    /// runtime is shutting down.
+    ///
+    /// ```
+    /// # use impedance::adaptive::{AdaptiveError, AdaptiveFuture, Token};
+    /// # async fn _doc(payload: String) {
+    /// match AdaptiveFuture::try_new(Token::new(), move || payload.parse::<i32>()).await {
+    ///     Ok(parsed) => println!("parsed {:?}", parsed),
+    ///     Err(e) if e.is_cancelled() => println!("shutting down"),
+    ///     Err(e) => println!("failed: {}", e),
+    /// }
+    /// # }
+    /// ```
+    pub fn try_new(token: Token, future: F) -> TryAdaptiveFuture<O, F> {
+        TryAdaptiveFuture {
+            inner: TimedBlockingFuture::new(token, None, None, future),
+        }
+    }
+}
+
+impl<O: 'static> AdaptiveFuture<O, Box<dyn FnOnce() -> O + Send>> {
+    /// Create a new `AdaptiveFuture` whose work is passed a [`Cancellation`](Cancellation),
+    /// which is cancelled when this `AdaptiveFuture` is dropped. Work that was moved onto a
+    /// thread keeps running when it is, so long-running work (for example, a loop parsing or
+    /// compressing a large payload) can check
+    /// [`is_cancelled`](Cancellation::is_cancelled) to stop early.
+    ///
+    /// ```
+    /// # use impedance::adaptive::{AdaptiveFuture, Token};
+    /// # async fn _doc(chunks: Vec<Vec<u8>>) {
+    /// let compressed = AdaptiveFuture::cancellable(Token::new(), move |cancellation| {
+    ///     let mut compressed = Vec::new();
+    ///     for chunk in chunks {
+    ///         if cancellation.is_cancelled() {
+    ///             // Nobody is waiting for this anymore
+    ///             break;
+    ///         }
+    ///         compressed.extend(chunk);
+    ///     }
+    ///     compressed
+    /// })
+    /// .await;
+    /// # }
+    /// ```
+    pub fn cancellable<G>(token: Token, future: G) -> Self
+    where
+        G: FnOnce(&Cancellation) -> O + Send + 'static,
+    {
+        let cancellation = Cancellation::default();
+        let passed = cancellation.clone();
+        AdaptiveFuture {
+            inner: TimedBlockingFuture::new(
+                token,
+                None,
+                Some(cancellation),
+                Box::new(move || future(&passed)),
+            ),
+            cancelled: false,
         }
     }
 }
@@ -96,6 +268,45 @@ impl<O: Send + 'static, F: FnOnce() -> O + Send + 'static> Future for AdaptiveFu
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
-        this.inner.poll(cx)
+        if *this.cancelled {
+            return Poll::Pending;
+        }
+        match this.inner.poll(cx) {
+            Poll::Ready(Ok(val)) => Poll::Ready(val),
+            Poll::Ready(Err(e)) => match e.try_into_panic() {
+                Ok(panic) => resume_unwind(panic),
+                // We have nothing to return: something else is shutting everything down, and
+                // the task polling us will likely be shutting down as well
+                Err(_) => {
+                    *this.cancelled = true;
+                    Poll::Pending
+                }
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A [`Future`][Future] representing *blocking work*, that resolves to an
+/// [`AdaptiveError`](AdaptiveError) instead of panicking or never completing when the work
+/// can't produce its result.
+///
+/// Created with [`AdaptiveFuture::try_new`](AdaptiveFuture::try_new).
+#[pin_project]
+pub struct TryAdaptiveFuture<O, F> {
+    #[pin]
+    inner: TimedBlockingFuture<O, F>,
+}
+
+impl<O: Send + 'static, F: FnOnce() -> O + Send + 'static> Future for TryAdaptiveFuture<O, F> {
+    type Output = Result<O, AdaptiveError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        // Work that runs inline (or in place) panics in here
+        match catch_unwind(AssertUnwindSafe(|| this.inner.poll(cx))) {
+            Ok(poll) => poll,
+            Err(panic) => Poll::Ready(Err(AdaptiveError::panicked(panic))),
+        }
     }
 }
@@ -0,0 +1,367 @@
+use std::{
+    fmt::{self, Write},
+    time::Duration,
+};
+
+use super::{
+    core::stats,
+    policy::AdaptiveState,
+    stats::{TokenStats, DURATION_BUCKETS},
+};
+
+/// The `Content-Type` to serve [`render_openmetrics`] with
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Render the [`stats`](super::stats) of every live adaptive [`Token`](super::Token) in the
+/// [OpenMetrics](https://openmetrics.io) text format, which Prometheus can scrape. This is
+/// meant to be served (with [`OPENMETRICS_CONTENT_TYPE`]) from an existing metrics endpoint.
+///
+/// Every metric has a `token` label, which is the name of the `Token`. `Token`'s without a
+/// name (which may be created per request) are summed up into a single `unnamed` series, so
+/// they don't create a new series for every `Token`. The metrics are:
+/// - `impedance_token_runs_total`: a counter of the work run, with a `placement` label that is
+///   either `inline`, `spawn` or `block_in_place`
+/// - `impedance_token_skipped_total`: a counter of the work that was skipped, as its
+///   [`AdaptiveFuture`](super::AdaptiveFuture) was dropped before a thread picked it up
+/// - `impedance_token_run_duration_seconds`: a histogram of the *wall-time* of the work
+/// - `impedance_token_state`: a gauge of how many `Token`'s will run their next piece of work
+///   in each `state` (which is `1` or `0` for named `Token`'s)
+/// - `impedance_token_switches_total`: a counter of how often the `Token` has switched
+///   between running work inline and on a thread
+pub fn render_openmetrics() -> String {
+    let mut out = String::new();
+    write_openmetrics(&mut out, &stats()).expect("writing to a String can't fail");
+    out
+}
+
+const STATES: [(AdaptiveState, &str); 3] = [
+    (AdaptiveState::Inline, "inline"),
+    (AdaptiveState::Spawn, "spawn"),
+    (AdaptiveState::BlockInPlace, "block_in_place"),
+];
+
+/// The stats of all the `Token`'s with the same `token` label
+struct Series {
+    label: String,
+    inline_runs: u64,
+    spawned_runs: u64,
+    blocked_in_place_runs: u64,
+    skipped_runs: u64,
+    total_time: Duration,
+    durations: Vec<(Duration, u64)>,
+    /// How many `Token`'s are in each of `STATES`
+    states: [u64; 3],
+    switches: u64,
+}
+
+impl Series {
+    fn new(label: String) -> Self {
+        Series {
+            label,
+            inline_runs: 0,
+            spawned_runs: 0,
+            blocked_in_place_runs: 0,
+            skipped_runs: 0,
+            total_time: Duration::ZERO,
+            durations: DURATION_BUCKETS.iter().map(|bound| (*bound, 0)).collect(),
+            states: [0; 3],
+            switches: 0,
+        }
+    }
+
+    fn add(&mut self, stats: &TokenStats) {
+        self.inline_runs += stats.inline_runs;
+        self.spawned_runs += stats.spawned_runs;
+        self.blocked_in_place_runs += stats.blocked_in_place_runs;
+        self.skipped_runs += stats.skipped_runs;
+        self.total_time += stats.total_time;
+        for ((_, total), (_, count)) in self.durations.iter_mut().zip(&stats.durations) {
+            *total += count;
+        }
+        for ((state, _), count) in STATES.iter().zip(&mut self.states) {
+            *count += (stats.state == *state) as u64;
+        }
+        self.switches += stats.switches;
+    }
+}
+
+fn series(stats: &[TokenStats]) -> Vec<Series> {
+    let mut series: Vec<Series> = Vec::new();
+    for stats in stats {
+        let label = token_label(stats);
+        let index = match series.iter().position(|s| s.label == label) {
+            Some(index) => index,
+            None => {
+                series.push(Series::new(label));
+                series.len() - 1
+            }
+        };
+        series[index].add(stats);
+    }
+    series
+}
+
+fn write_openmetrics(out: &mut String, stats: &[TokenStats]) -> fmt::Result {
+    let series = series(stats);
+
+    writeln!(out, "# TYPE impedance_token_runs counter")?;
+    writeln!(
+        out,
+        "# HELP impedance_token_runs Pieces of work run, by where they ran."
+    )?;
+    for series in &series {
+        for (placement, runs) in &[
+            ("inline", series.inline_runs),
+            ("spawn", series.spawned_runs),
+            ("block_in_place", series.blocked_in_place_runs),
+        ] {
+            writeln!(
+                out,
+                "impedance_token_runs_total{{{},placement=\"{}\"}} {}",
+                series.label, placement, runs
+            )?;
+        }
+    }
+
+    writeln!(out, "# TYPE impedance_token_skipped counter")?;
+    writeln!(
+        out,
+        "# HELP impedance_token_skipped Pieces of work skipped, as nobody was waiting for them."
+    )?;
+    for series in &series {
+        writeln!(
+            out,
+            "impedance_token_skipped_total{{{}}} {}",
+            series.label, series.skipped_runs
+        )?;
+    }
+
+    writeln!(out, "# TYPE impedance_token_run_duration_seconds histogram")?;
+    writeln!(out, "# UNIT impedance_token_run_duration_seconds seconds")?;
+    writeln!(
+        out,
+        "# HELP impedance_token_run_duration_seconds The wall-time of the work."
+    )?;
+    for series in &series {
+        let mut count = 0;
+        for (bound, cumulative) in &series.durations {
+            count = *cumulative;
+            writeln!(
+                out,
+                "impedance_token_run_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                series.label,
+                float(bound.as_secs_f64()),
+                cumulative
+            )?;
+        }
+        // The counters are read independently, but buckets must never decrease
+        let count =
+            count.max(series.inline_runs + series.spawned_runs + series.blocked_in_place_runs);
+        writeln!(
+            out,
+            "impedance_token_run_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            series.label, count
+        )?;
+        writeln!(
+            out,
+            "impedance_token_run_duration_seconds_sum{{{}}} {}",
+            series.label,
+            float(series.total_time.as_secs_f64())
+        )?;
+        writeln!(
+            out,
+            "impedance_token_run_duration_seconds_count{{{}}} {}",
+            series.label, count
+        )?;
+    }
+
+    writeln!(out, "# TYPE impedance_token_state gauge")?;
+    writeln!(
+        out,
+        "# HELP impedance_token_state How many tokens will run their next piece of work where."
+    )?;
+    for series in &series {
+        for ((_, name), count) in STATES.iter().zip(&series.states) {
+            writeln!(
+                out,
+                "impedance_token_state{{{},state=\"{}\"}} {}",
+                series.label, name, count
+            )?;
+        }
+    }
+
+    writeln!(out, "# TYPE impedance_token_switches counter")?;
+    writeln!(
+        out,
+        "# HELP impedance_token_switches Switches between running work inline and on a thread."
+    )?;
+    for series in &series {
+        writeln!(
+            out,
+            "impedance_token_switches_total{{{}}} {}",
+            series.label, series.switches
+        )?;
+    }
+
+    writeln!(out, "# EOF")
+}
+
+/// A float in its canonical OpenMetrics form, which always has a fraction (like `1.0`)
+fn float(value: f64) -> String {
+    let value = value.to_string();
+    if value.contains('.') || value.contains('e') {
+        value
+    } else {
+        format!("{}.0", value)
+    }
+}
+
+/// The `token` label of a `Token`
+fn token_label(stats: &TokenStats) -> String {
+    let mut label = String::from("token=\"");
+    match &stats.name {
+        Some(name) => {
+            for c in name.chars() {
+                match c {
+                    '\\' => label.push_str("\\\\"),
+                    '"' => label.push_str("\\\""),
+                    '\n' => label.push_str("\\n"),
+                    c => label.push(c),
+                }
+            }
+        }
+        None => label.push_str("unnamed"),
+    }
+    label.push('"');
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let stats = TokenStats {
+            id: 3,
+            name: Some("say \"hi\"".to_string()),
+            state: AdaptiveState::Spawn,
+            inline_runs: 1,
+            spawned_runs: 2,
+            blocked_in_place_runs: 0,
+            skipped_runs: 4,
+            total_time: Duration::from_millis(3),
+            mean_time: Duration::from_millis(1),
+            max_time: Duration::from_millis(2),
+            switches: 1,
+            durations: DURATION_BUCKETS
+                .iter()
+                .map(|bound| {
+                    (
+                        *bound,
+                        if *bound < Duration::from_millis(5) {
+                            1
+                        } else {
+                            3
+                        },
+                    )
+                })
+                .collect(),
+        };
+
+        let mut out = String::new();
+        write_openmetrics(&mut out, &[stats]).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        let label = r#"token="say \"hi\"""#;
+        for expected in &[
+            format!(
+                r#"impedance_token_runs_total{{{},placement="inline"}} 1"#,
+                label
+            ),
+            format!(
+                r#"impedance_token_runs_total{{{},placement="spawn"}} 2"#,
+                label
+            ),
+            format!(
+                r#"impedance_token_run_duration_seconds_bucket{{{},le="0.001"}} 1"#,
+                label
+            ),
+            format!(
+                r#"impedance_token_run_duration_seconds_bucket{{{},le="0.005"}} 3"#,
+                label
+            ),
+            format!(
+                r#"impedance_token_run_duration_seconds_bucket{{{},le="1.0"}} 3"#,
+                label
+            ),
+            format!(
+                r#"impedance_token_run_duration_seconds_bucket{{{},le="+Inf"}} 3"#,
+                label
+            ),
+            format!(
+                r#"impedance_token_run_duration_seconds_sum{{{}}} 0.003"#,
+                label
+            ),
+            format!(
+                r#"impedance_token_run_duration_seconds_count{{{}}} 3"#,
+                label
+            ),
+            format!(r#"impedance_token_state{{{},state="inline"}} 0"#, label),
+            format!(r#"impedance_token_state{{{},state="spawn"}} 1"#, label),
+            format!(r#"impedance_token_switches_total{{{}}} 1"#, label),
+            format!(r#"impedance_token_skipped_total{{{}}} 4"#, label),
+        ] {
+            assert!(lines.contains(&expected.as_str()), "missing {}", expected);
+        }
+        assert_eq!(lines.last(), Some(&"# EOF"));
+    }
+
+    #[test]
+    fn test_unnamed_summed() {
+        let unnamed = |id, state| TokenStats {
+            id,
+            name: None,
+            state,
+            inline_runs: 1,
+            spawned_runs: 0,
+            blocked_in_place_runs: 0,
+            skipped_runs: 0,
+            total_time: Duration::from_millis(1),
+            mean_time: Duration::from_millis(1),
+            max_time: Duration::from_millis(1),
+            switches: 0,
+            durations: DURATION_BUCKETS.iter().map(|bound| (*bound, 1)).collect(),
+        };
+
+        let mut out = String::new();
+        write_openmetrics(
+            &mut out,
+            &[
+                unnamed(1, AdaptiveState::Inline),
+                unnamed(2, AdaptiveState::Inline),
+                unnamed(3, AdaptiveState::Spawn),
+            ],
+        )
+        .unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        for expected in &[
+            r#"impedance_token_runs_total{token="unnamed",placement="inline"} 3"#,
+            r#"impedance_token_run_duration_seconds_count{token="unnamed"} 3"#,
+            r#"impedance_token_run_duration_seconds_sum{token="unnamed"} 0.003"#,
+            r#"impedance_token_state{token="unnamed",state="inline"} 2"#,
+            r#"impedance_token_state{token="unnamed",state="spawn"} 1"#,
+        ] {
+            assert!(lines.contains(expected), "missing {}", expected);
+        }
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|line| line.starts_with("impedance_token_switches_total"))
+                .count(),
+            1
+        );
+    }
+}
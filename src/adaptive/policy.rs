@@ -0,0 +1,393 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use super::sketch::Sketch;
+
+/// Where an [`AdaptiveFuture`](super::AdaptiveFuture) runs its work
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum AdaptiveState {
+    /// Inline, in the [`poll`](std::future::Future::poll) implementation
+    #[default]
+    Inline,
+    /// On another thread, with the [`BlockingSpawner`](super::BlockingSpawner) of the `Token`,
+    /// which is the `spawn_blocking` of the enabled runtime by default
+    Spawn,
+    /// On the current thread, with [`block_in_place`](tokio::task::block_in_place), which
+    /// first hands off the other tasks of the current worker thread, and avoids moving the
+    /// work (and the data it captures) to another thread.
+    ///
+    /// `block_in_place` only works on multi-threaded `tokio` runtimes, so this falls back to
+    /// [`AdaptiveState::Spawn`] everywhere else (including with `async-std`).
+    BlockInPlace,
+}
+
+/// The result of running a piece of work, passed to [`SchedulingPolicy::observe`]
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct Outcome {
+    /// The *wall-time* the work took
+    pub elapsed: Duration,
+    /// Where the work was run
+    pub placement: AdaptiveState,
+    /// The cutoff that was configured for the work
+    pub cutoff: Duration,
+    /// The size passed to [`AdaptiveFuture::with_cost_hint`](super::AdaptiveFuture::with_cost_hint),
+    /// if any
+    pub cost_hint: Option<usize>,
+}
+
+/// A `SchedulingPolicy` decides where the work associated with a [`Token`](super::Token) is run.
+///
+/// Before an [`AdaptiveFuture`](super::AdaptiveFuture) runs its work, it asks its `Token`'s
+/// policy where to run it with [`decide`](SchedulingPolicy::decide). Once the work has finished
+/// (wherever it ran), the policy is told how it went with
+/// [`observe`](SchedulingPolicy::observe).
+///
+/// Policies are shared by all the work using a `Token`, possibly on many threads at once, so
+/// they have to handle their own synchronization. They are called on every piece of work, so
+/// they should avoid locks: the built-in policies only use atomics.
+///
+/// ```
+/// use impedance::adaptive::{AdaptiveState, Outcome, SchedulingPolicy, Token};
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// /// Spawn every other piece of work
+/// #[derive(Default)]
+/// struct Alternate(AtomicUsize);
+///
+/// impl SchedulingPolicy for Alternate {
+///     fn decide(&self) -> AdaptiveState {
+///         if self.0.fetch_add(1, Ordering::Relaxed) % 2 == 0 {
+///             AdaptiveState::Inline
+///         } else {
+///             AdaptiveState::Spawn
+///         }
+///     }
+///
+///     fn observe(&self, _outcome: &Outcome) {}
+/// }
+///
+/// let token = Token::with_policy(Alternate::default());
+/// ```
+pub trait SchedulingPolicy: Send + Sync + 'static {
+    /// Decide where to run the next piece of work
+    fn decide(&self) -> AdaptiveState;
+
+    /// Observe the outcome of a piece of work
+    fn observe(&self, outcome: &Outcome);
+}
+
+/// Share a policy with a `Token`, for example to inspect its state later
+impl<P: SchedulingPolicy + ?Sized> SchedulingPolicy for Arc<P> {
+    fn decide(&self) -> AdaptiveState {
+        (**self).decide()
+    }
+
+    fn observe(&self, outcome: &Outcome) {
+        (**self).observe(outcome)
+    }
+}
+
+/// The default [`SchedulingPolicy`]: run work where the *last* piece of work should
+/// have been run, based only on its *wall-time*.
+#[derive(Debug, Default)]
+pub struct LastSample {
+    spawn: AtomicBool,
+}
+
+impl SchedulingPolicy for LastSample {
+    fn decide(&self) -> AdaptiveState {
+        if self.spawn.load(Ordering::Relaxed) {
+            AdaptiveState::Spawn
+        } else {
+            AdaptiveState::Inline
+        }
+    }
+
+    fn observe(&self, outcome: &Outcome) {
+        let spawn = outcome.elapsed > outcome.cutoff;
+        // Avoid writing to the shared cache-line if we can
+        if self.spawn.load(Ordering::Relaxed) != spawn {
+            self.spawn.store(spawn, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A [`SchedulingPolicy`] that decides based on an exponentially-weighted moving average of
+/// *wall-times*. See [`Token::ewma`](super::Token::ewma).
+#[derive(Debug)]
+pub struct Ewma {
+    alpha: f64,
+    /// The bits of the average (an `f64`), in nanoseconds. `NaN` before the first sample.
+    average: AtomicU64,
+    spawn: AtomicBool,
+}
+
+impl Ewma {
+    /// Create a new `Ewma` policy with the smoothing factor `alpha`
+    ///
+    /// # Panics
+    /// If `alpha` is not in `(0.0, 1.0]`
+    pub fn new(alpha: f64) -> Self {
+        assert!(
+            alpha > 0.0 && alpha <= 1.0,
+            "ewma smoothing factor must be in (0.0, 1.0], got {}",
+            alpha
+        );
+        Ewma {
+            alpha,
+            average: AtomicU64::new(f64::NAN.to_bits()),
+            spawn: AtomicBool::new(false),
+        }
+    }
+}
+
+impl SchedulingPolicy for Ewma {
+    fn decide(&self) -> AdaptiveState {
+        if self.spawn.load(Ordering::Relaxed) {
+            AdaptiveState::Spawn
+        } else {
+            AdaptiveState::Inline
+        }
+    }
+
+    fn observe(&self, outcome: &Outcome) {
+        let sample = outcome.elapsed.as_nanos() as f64;
+        let next = |bits| {
+            let avg = f64::from_bits(bits);
+            // The first sample seeds the average, so a `Token` doesn't have to
+            // warm up from 0
+            if avg.is_nan() {
+                sample
+            } else {
+                self.alpha * sample + (1.0 - self.alpha) * avg
+            }
+        };
+        let prev = self
+            .average
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some(next(bits).to_bits())
+            })
+            .unwrap_or_else(|bits| bits);
+        self.spawn.store(
+            next(prev) > outcome.cutoff.as_nanos() as f64,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// A [`SchedulingPolicy`] with separate cutoffs for moving work onto a thread and back inline.
+/// See [`Token::hysteresis`](super::Token::hysteresis).
+#[derive(Debug)]
+pub struct Hysteresis {
+    spawn_above: Duration,
+    inline_below: Duration,
+    min_consecutive: u32,
+    /// The current state in the high bit (set when spawning), and how many times in a row its
+    /// cutoff has been crossed in the rest
+    state: AtomicU64,
+}
+
+const SPAWN_BIT: u64 = 1 << 63;
+
+impl Hysteresis {
+    /// Create a new `Hysteresis` policy
+    ///
+    /// # Panics
+    /// If `inline_below` is larger than `spawn_above`, or `min_consecutive` is `0`
+    pub fn new(spawn_above: Duration, inline_below: Duration, min_consecutive: u32) -> Self {
+        assert!(
+            inline_below <= spawn_above,
+            "hysteresis inline_below ({:?}) must not be larger than spawn_above ({:?})",
+            inline_below,
+            spawn_above
+        );
+        assert!(
+            min_consecutive > 0,
+            "hysteresis min_consecutive must be at least 1"
+        );
+        Hysteresis {
+            spawn_above,
+            inline_below,
+            min_consecutive,
+            state: AtomicU64::new(0),
+        }
+    }
+}
+
+impl SchedulingPolicy for Hysteresis {
+    fn decide(&self) -> AdaptiveState {
+        if self.state.load(Ordering::Relaxed) & SPAWN_BIT != 0 {
+            AdaptiveState::Spawn
+        } else {
+            AdaptiveState::Inline
+        }
+    }
+
+    fn observe(&self, outcome: &Outcome) {
+        let _ = self
+            .state
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |state| {
+                let spawning = state & SPAWN_BIT != 0;
+                let crossed = if spawning {
+                    outcome.elapsed < self.inline_below
+                } else {
+                    outcome.elapsed > self.spawn_above
+                };
+                let streak = if crossed { (state & !SPAWN_BIT) + 1 } else { 0 };
+
+                Some(if streak >= u64::from(self.min_consecutive) {
+                    // Flip the current state
+                    (state & SPAWN_BIT) ^ SPAWN_BIT
+                } else {
+                    (state & SPAWN_BIT) | streak
+                })
+            });
+    }
+}
+
+/// A [`SchedulingPolicy`] that decides based on a quantile of recent *wall-times*.
+/// See [`Token::quantile`](super::Token::quantile).
+#[derive(Debug)]
+pub struct Quantile {
+    q: f64,
+    sketch: Sketch,
+    spawn: AtomicBool,
+}
+
+impl Quantile {
+    /// Create a new `Quantile` policy for the `q`'th quantile
+    ///
+    /// # Panics
+    /// If `q` is not in `(0.0, 1.0]`
+    pub fn new(q: f64) -> Self {
+        assert!(
+            q > 0.0 && q <= 1.0,
+            "quantile must be in (0.0, 1.0], got {}",
+            q
+        );
+        Quantile {
+            q,
+            sketch: Sketch::default(),
+            spawn: AtomicBool::new(false),
+        }
+    }
+}
+
+impl SchedulingPolicy for Quantile {
+    fn decide(&self) -> AdaptiveState {
+        if self.spawn.load(Ordering::Relaxed) {
+            AdaptiveState::Spawn
+        } else {
+            AdaptiveState::Inline
+        }
+    }
+
+    fn observe(&self, outcome: &Outcome) {
+        self.sketch.record(outcome.elapsed);
+        let spawn = self
+            .sketch
+            .quantile(self.q)
+            .is_some_and(|d| d > outcome.cutoff);
+        self.spawn.store(spawn, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CUTOFF: Duration = Duration::from_micros(100);
+
+    fn observe(policy: &impl SchedulingPolicy, elapsed: Duration) {
+        policy.observe(&Outcome {
+            elapsed,
+            placement: policy.decide(),
+            cutoff: CUTOFF,
+            cost_hint: None,
+        });
+    }
+
+    #[test]
+    fn test_last_sample_flaps() {
+        let policy = LastSample::default();
+        observe(&policy, Duration::from_micros(10));
+        assert_eq!(policy.decide(), AdaptiveState::Inline);
+        observe(&policy, Duration::from_millis(1));
+        assert_eq!(policy.decide(), AdaptiveState::Spawn);
+        observe(&policy, Duration::from_micros(10));
+        assert_eq!(policy.decide(), AdaptiveState::Inline);
+    }
+
+    #[test]
+    fn test_ewma_smooths_outliers() {
+        let policy = Ewma::new(0.1);
+        for _ in 0..10 {
+            observe(&policy, Duration::from_micros(10));
+        }
+        // 0.1 * 500 + 0.9 * 10 = 59us
+        observe(&policy, Duration::from_micros(500));
+        assert_eq!(policy.decide(), AdaptiveState::Inline);
+
+        // But consistently slow work still moves to a thread
+        for _ in 0..10 {
+            observe(&policy, Duration::from_micros(500));
+        }
+        assert_eq!(policy.decide(), AdaptiveState::Spawn);
+        observe(&policy, Duration::from_micros(10));
+        assert_eq!(policy.decide(), AdaptiveState::Spawn);
+    }
+
+    #[test]
+    fn test_hysteresis_band() {
+        let policy = Hysteresis::new(Duration::from_micros(150), Duration::from_micros(50), 2);
+
+        // Work in the band never moves
+        for _ in 0..10 {
+            observe(&policy, Duration::from_micros(100));
+        }
+        assert_eq!(policy.decide(), AdaptiveState::Inline);
+
+        // A single slow run isn't enough, and the streak is reset
+        observe(&policy, Duration::from_micros(200));
+        observe(&policy, Duration::from_micros(100));
+        observe(&policy, Duration::from_micros(200));
+        assert_eq!(policy.decide(), AdaptiveState::Inline);
+        observe(&policy, Duration::from_micros(200));
+        assert_eq!(policy.decide(), AdaptiveState::Spawn);
+
+        // Work in the band stays spawned
+        for _ in 0..10 {
+            observe(&policy, Duration::from_micros(100));
+        }
+        assert_eq!(policy.decide(), AdaptiveState::Spawn);
+        observe(&policy, Duration::from_micros(10));
+        assert_eq!(policy.decide(), AdaptiveState::Spawn);
+        observe(&policy, Duration::from_micros(10));
+        assert_eq!(policy.decide(), AdaptiveState::Inline);
+    }
+
+    #[test]
+    fn test_quantile_tail() {
+        let p50 = Quantile::new(0.5);
+        let p99 = Quantile::new(0.99);
+        for i in 0..100 {
+            // 5% of the work blocks for 20ms
+            let elapsed = if i % 20 == 0 {
+                Duration::from_millis(20)
+            } else {
+                Duration::from_micros(10)
+            };
+            observe(&p50, elapsed);
+            observe(&p99, elapsed);
+        }
+        assert_eq!(p50.decide(), AdaptiveState::Inline);
+        assert_eq!(p99.decide(), AdaptiveState::Spawn);
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    convert::TryFrom,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+/// Bits of precision kept below the leading bit of each sample: each power-of-2
+/// range of *wall-times* is split into `1 << SUB_BITS` buckets.
+const SUB_BITS: u32 = 2;
+const SUB: usize = 1 << SUB_BITS;
+const BUCKETS: usize = SUB + (64 - SUB_BITS as usize) * SUB;
+
+/// Once this many samples are recorded, all counts are halved, so recent work outweighs old
+/// work.
+const DECAY_AT: u32 = 1024;
+
+/// A compact streaming histogram of *wall-times*, used to estimate quantiles.
+///
+/// Samples are bucketed log-linearly (with roughly 25% relative error), in nanoseconds.
+/// Recording samples concurrently is lock-free, and as a consequence the counts are only
+/// approximately consistent with each other, which is fine for estimating quantiles.
+pub(crate) struct Sketch {
+    counts: [AtomicU32; BUCKETS],
+    total: AtomicU32,
+}
+
+impl Default for Sketch {
+    fn default() -> Self {
+        Sketch {
+            counts: std::array::from_fn(|_| AtomicU32::new(0)),
+            total: AtomicU32::new(0),
+        }
+    }
+}
+
+impl std::fmt::Debug for Sketch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sketch")
+            .field("total", &self.total.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+fn bucket(nanos: u64) -> usize {
+    if nanos < SUB as u64 {
+        return nanos as usize;
+    }
+    let octave = 63 - nanos.leading_zeros();
+    let sub = (nanos >> (octave - SUB_BITS)) as usize & (SUB - 1);
+    SUB + (octave - SUB_BITS) as usize * SUB + sub
+}
+
+/// The largest value that falls into `bucket`
+fn upper_bound(bucket: usize) -> u64 {
+    if bucket < SUB {
+        return bucket as u64;
+    }
+    let shift = ((bucket - SUB) / SUB) as u32;
+    let sub = ((bucket - SUB) % SUB) as u64;
+    // Computed as `lower + (width - 1)` so the top bucket doesn't overflow
+    ((SUB as u64 + sub) << shift) + ((1 << shift) - 1)
+}
+
+impl Sketch {
+    pub(crate) fn record(&self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.counts[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+
+        // Only the sample that reaches `DECAY_AT` decays the counts
+        if self.total.fetch_add(1, Ordering::Relaxed) + 1 == DECAY_AT {
+            let mut total = 0;
+            for count in self.counts.iter() {
+                let prev =
+                    count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| Some(c / 2));
+                total += prev.unwrap_or(0) / 2;
+            }
+            self.total.store(total, Ordering::Relaxed);
+        }
+    }
+
+    /// Estimate the `q`'th quantile of the recorded samples, rounding up to the top of its
+    /// bucket, so decisions err towards spawning.
+    pub(crate) fn quantile(&self, q: f64) -> Option<Duration> {
+        let total = self.total.load(Ordering::Relaxed);
+        if total == 0 {
+            return None;
+        }
+        let rank = ((q * total as f64).ceil() as u32).max(1);
+
+        let mut seen = 0;
+        let mut last = None;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count == 0 {
+                continue;
+            }
+            seen += count;
+            last = Some(bucket);
+            if seen >= rank {
+                break;
+            }
+        }
+        // Concurrent decays can leave `total` ahead of the counts, in which case
+        // the largest sample is the best estimate.
+        last.map(|bucket| Duration::from_nanos(upper_bound(bucket)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        for nanos in [0, 1, 3, 4, 5, 7, 8, 100, 1_000_000, u64::MAX] {
+            let b = bucket(nanos);
+            assert!(b < BUCKETS);
+            assert!(upper_bound(b) >= nanos, "{}", nanos);
+            if b > 0 {
+                assert!(upper_bound(b - 1) < nanos, "{}", nanos);
+            }
+        }
+        assert_eq!(upper_bound(BUCKETS - 1), u64::MAX);
+    }
+
+    #[test]
+    fn test_quantiles() {
+        let sketch = Sketch::default();
+        assert_eq!(sketch.quantile(0.5), None);
+
+        for _ in 0..95 {
+            sketch.record(Duration::from_micros(10));
+        }
+        for _ in 0..5 {
+            sketch.record(Duration::from_millis(20));
+        }
+
+        let p50 = sketch.quantile(0.5).unwrap();
+        assert!(p50 >= Duration::from_micros(10) && p50 < Duration::from_micros(13));
+        let p99 = sketch.quantile(0.99).unwrap();
+        assert!(p99 >= Duration::from_millis(20) && p99 < Duration::from_millis(25));
+    }
+
+    #[test]
+    fn test_decay() {
+        let sketch = Sketch::default();
+        for _ in 0..DECAY_AT - 1 {
+            sketch.record(Duration::from_millis(20));
+        }
+        for _ in 0..DECAY_AT {
+            sketch.record(Duration::from_micros(10));
+        }
+        // The old, slow, samples have been decayed away
+        assert!(sketch.quantile(0.5).unwrap() < Duration::from_micros(13));
+    }
+}
@@ -0,0 +1,129 @@
+use std::{sync::Arc, thread};
+
+/// Work handed to a [`BlockingSpawner`]. It catches its own panics and sends its result back to
+/// the [`AdaptiveFuture`](super::AdaptiveFuture) waiting on it.
+pub type BlockingWork = Box<dyn FnOnce() + Send>;
+
+/// A `BlockingSpawner` moves the work of an [`AdaptiveFuture`](super::AdaptiveFuture) onto a
+/// thread where it is ok to block, when its [`Token`](super::Token) decides to
+/// [`Spawn`](super::AdaptiveState::Spawn) it.
+///
+/// By default, `Token`'s use the `spawn_blocking` of the enabled runtime (`TokioSpawner`,
+/// `AsyncStdSpawner` or `SmolSpawner`), or [`ThreadSpawner`] if no runtime feature is enabled.
+/// Other executors can be plugged in with [`TokenBuilder::spawner`](super::TokenBuilder::spawner)
+/// and [`Token::always_spawn_with`](super::Token::always_spawn_with).
+///
+/// Spawners only have to run the work: it never panics, and it wakes up the
+/// `AdaptiveFuture` itself.
+///
+/// ```
+/// use impedance::adaptive::{BlockingSpawner, BlockingWork, Token};
+/// use std::sync::{mpsc, Mutex};
+/// use std::thread;
+///
+/// /// Run all the work on a single, dedicated thread
+/// struct Dedicated(Mutex<mpsc::Sender<BlockingWork>>);
+///
+/// impl Dedicated {
+///     fn new() -> Self {
+///         let (tx, rx) = mpsc::channel::<BlockingWork>();
+///         thread::spawn(move || rx.into_iter().for_each(|work| work()));
+///         Dedicated(Mutex::new(tx))
+///     }
+/// }
+///
+/// impl BlockingSpawner for Dedicated {
+///     fn spawn(&self, work: BlockingWork) {
+///         // Dropping work that can't be sent tells the `AdaptiveFuture` it was cancelled
+///         let _ = self.0.lock().unwrap().send(work);
+///     }
+/// }
+///
+/// let token = Token::builder().spawner(Dedicated::new()).build();
+/// ```
+pub trait BlockingSpawner: Send + Sync + 'static {
+    /// Start running `work` on a thread where it is ok to block
+    fn spawn(&self, work: BlockingWork);
+}
+
+/// Share a spawner between `Token`'s
+impl<S: BlockingSpawner + ?Sized> BlockingSpawner for Arc<S> {
+    fn spawn(&self, work: BlockingWork) {
+        (**self).spawn(work)
+    }
+}
+
+/// Moves every piece of work onto a new thread with [`std::thread::spawn`]. This is the default
+/// spawner when no runtime feature is enabled.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadSpawner;
+
+impl BlockingSpawner for ThreadSpawner {
+    fn spawn(&self, work: BlockingWork) {
+        thread::spawn(work);
+    }
+}
+
+/// Moves work onto a thread with [`tokio::task::spawn_blocking`]
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioSpawner;
+
+#[cfg(feature = "tokio")]
+impl BlockingSpawner for TokioSpawner {
+    fn spawn(&self, work: BlockingWork) {
+        // Dropping the `JoinHandle` detaches the work
+        tokio::task::spawn_blocking(work);
+    }
+}
+
+/// Moves work onto a thread with [`async_std::task::spawn_blocking`]
+#[cfg(feature = "async-std-experimental")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncStdSpawner;
+
+#[cfg(feature = "async-std-experimental")]
+impl BlockingSpawner for AsyncStdSpawner {
+    fn spawn(&self, work: BlockingWork) {
+        // Dropping the `JoinHandle` detaches the work
+        async_std::task::spawn_blocking(work);
+    }
+}
+
+/// Moves work onto a thread with [`blocking::unblock`], the thread pool used by `smol`
+#[cfg(feature = "smol")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SmolSpawner;
+
+#[cfg(feature = "smol")]
+impl BlockingSpawner for SmolSpawner {
+    fn spawn(&self, work: BlockingWork) {
+        // Dropping the `Task` would cancel the work
+        blocking::unblock(work).detach();
+    }
+}
+
+/// The spawner of the enabled runtime
+#[cfg(feature = "tokio")]
+pub(crate) fn default_spawner() -> &'static dyn BlockingSpawner {
+    &TokioSpawner
+}
+
+#[cfg(feature = "async-std-experimental")]
+pub(crate) fn default_spawner() -> &'static dyn BlockingSpawner {
+    &AsyncStdSpawner
+}
+
+#[cfg(feature = "smol")]
+pub(crate) fn default_spawner() -> &'static dyn BlockingSpawner {
+    &SmolSpawner
+}
+
+#[cfg(not(any(
+    feature = "tokio",
+    feature = "async-std-experimental",
+    feature = "smol"
+)))]
+pub(crate) fn default_spawner() -> &'static dyn BlockingSpawner {
+    &ThreadSpawner
+}
@@ -0,0 +1,168 @@
+use std::{
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use super::policy::AdaptiveState;
+
+/// The upper bounds of the buckets of [`TokenStats::durations`]
+pub const DURATION_BUCKETS: [Duration; 12] = [
+    Duration::from_micros(10),
+    Duration::from_micros(25),
+    Duration::from_micros(50),
+    Duration::from_micros(100),
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// A snapshot of what an adaptive [`Token`](super::Token) has been doing, from
+/// [`Token::stats`](super::Token::stats) or [`stats`](super::stats).
+///
+/// The counters are read independently of each other while work may be running, so they
+/// may be slightly inconsistent with each other.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TokenStats {
+    /// Uniquely identifies the `Token` in this process
+    pub id: usize,
+    /// The name of the `Token`, if it has one
+    pub name: Option<String>,
+    /// Where the `Token` decided to run its last piece of work (or its initial state, if it
+    /// hasn't run any), ignoring probing and cost hints
+    pub state: AdaptiveState,
+    /// How many pieces of work were run inline
+    pub inline_runs: u64,
+    /// How many pieces of work were moved onto a thread
+    pub spawned_runs: u64,
+    /// How many pieces of work were run with [`AdaptiveState::BlockInPlace`]
+    pub blocked_in_place_runs: u64,
+    /// How many pieces of work were skipped because their
+    /// [`AdaptiveFuture`](super::AdaptiveFuture) was dropped before a thread picked them up
+    pub skipped_runs: u64,
+    /// The total *wall-time* of all the work
+    pub total_time: Duration,
+    /// The mean *wall-time* of the work
+    pub mean_time: Duration,
+    /// The longest *wall-time* of any piece of work
+    pub max_time: Duration,
+    /// How many times the `Token` switched between running work inline and on a thread
+    pub switches: u64,
+    /// For each bound in [`DURATION_BUCKETS`], how many pieces of work took at most that long
+    pub durations: Vec<(Duration, u64)>,
+}
+
+fn to_u8(state: AdaptiveState) -> u8 {
+    match state {
+        AdaptiveState::Inline => 0,
+        AdaptiveState::Spawn => 1,
+        AdaptiveState::BlockInPlace => 2,
+    }
+}
+
+fn from_u8(state: u8) -> AdaptiveState {
+    match state {
+        0 => AdaptiveState::Inline,
+        1 => AdaptiveState::Spawn,
+        _ => AdaptiveState::BlockInPlace,
+    }
+}
+
+/// The counters behind `TokenStats`
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    inline_runs: AtomicU64,
+    spawned_runs: AtomicU64,
+    blocked_in_place_runs: AtomicU64,
+    skipped_runs: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+    switches: AtomicU64,
+    /// The last placement, see `to_u8`
+    last: AtomicU8,
+    /// Non-cumulative counts for each of `DURATION_BUCKETS`, plus one for longer work
+    buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+}
+
+impl Stats {
+    pub(crate) fn new(initial: AdaptiveState) -> Self {
+        let stats = Stats::default();
+        stats.last.store(to_u8(initial), Ordering::Relaxed);
+        stats
+    }
+
+    /// Returns whether this is a switch from the last placement
+    pub(crate) fn record_placement(&self, placement: AdaptiveState) -> bool {
+        let placement = to_u8(placement);
+        // Avoid writing to the shared cache-line if we can
+        let switched = self.last.load(Ordering::Relaxed) != placement
+            && self.last.swap(placement, Ordering::Relaxed) != placement;
+        if switched {
+            self.switches.fetch_add(1, Ordering::Relaxed);
+        }
+        switched
+    }
+
+    pub(crate) fn record_run(&self, elapsed: Duration, placement: AdaptiveState) {
+        let nanos = elapsed.as_nanos() as u64;
+        match placement {
+            AdaptiveState::Inline => &self.inline_runs,
+            AdaptiveState::Spawn => &self.spawned_runs,
+            AdaptiveState::BlockInPlace => &self.blocked_in_place_runs,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        // Avoid writing to the shared cache-line if we can, which is almost always
+        if self.max_nanos.load(Ordering::Relaxed) < nanos {
+            self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+        }
+
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|bound| elapsed <= *bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_skipped(&self) {
+        self.skipped_runs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, id: usize, name: Option<&str>) -> TokenStats {
+        let inline_runs = self.inline_runs.load(Ordering::Relaxed);
+        let spawned_runs = self.spawned_runs.load(Ordering::Relaxed);
+        let total_nanos = self.total_nanos.load(Ordering::Relaxed);
+        let blocked_in_place_runs = self.blocked_in_place_runs.load(Ordering::Relaxed);
+        let runs = inline_runs + spawned_runs + blocked_in_place_runs;
+
+        let mut cumulative = 0;
+        let durations = DURATION_BUCKETS
+            .iter()
+            .zip(&self.buckets)
+            .map(|(bound, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                (*bound, cumulative)
+            })
+            .collect();
+
+        TokenStats {
+            id,
+            name: name.map(ToString::to_string),
+            state: from_u8(self.last.load(Ordering::Relaxed)),
+            inline_runs,
+            spawned_runs,
+            blocked_in_place_runs,
+            skipped_runs: self.skipped_runs.load(Ordering::Relaxed),
+            total_time: Duration::from_nanos(total_nanos),
+            mean_time: Duration::from_nanos(total_nanos.checked_div(runs).unwrap_or(0)),
+            max_time: Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed)),
+            switches: self.switches.load(Ordering::Relaxed),
+            durations,
+        }
+    }
+}
@@ -1,30 +1,220 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
 
-// TODO(guswynn): Do I need seqcst?
-static CURRENT: AtomicUsize = AtomicUsize::new(0);
+#[cfg(all(feature = "rayon", feature = "tokio"))]
+use crate::rayon::RayonPool;
 
-#[derive(Clone, Copy, Hash, PartialEq, Eq)]
+use super::{
+    core::{self, TokenState},
+    policy::{AdaptiveState, Ewma, Hysteresis, LastSample, Quantile, SchedulingPolicy},
+    spawner::BlockingSpawner,
+    stats::TokenStats,
+};
+
+/// Named `Token`'s, which live forever
+static REGISTRY: Lazy<Mutex<HashMap<Arc<str>, Token>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone)]
 pub(crate) enum TokenType {
-    AdhocAdaptive(usize),
+    Adaptive(Arc<TokenState>),
     AlwaysInline,
-    AlwaysSpawn,
+    /// With a custom spawner, from `Token::always_spawn_with`
+    AlwaysSpawn(Option<Arc<dyn BlockingSpawner>>),
+    AlwaysBlockInPlace,
 }
 
 /// `Token` is a type that is used to associate blocking work with itself and configure
 /// [`AdaptiveFuture`](super::AdaptiveFuture)'s.
 ///
 /// A token to configure and track *wall-times* for work in [`AdaptiveFuture`](super::AdaptiveFuture)'s
-#[derive(Clone, Copy, Hash, PartialEq, Eq)]
+///
+/// `Token`'s are cheap to clone: all clones share the same state, which is tracked without
+/// any global locks.
+#[derive(Clone)]
 pub struct Token(pub(crate) TokenType);
 
+impl Token {
+    /// Identifies the shared state of adaptive `Token`'s
+    fn key(&self) -> (u8, usize) {
+        match &self.0 {
+            TokenType::Adaptive(state) => (0, state.id()),
+            TokenType::AlwaysInline => (1, 0),
+            // `Token::always_spawn_with` shares its spawner between clones
+            TokenType::AlwaysSpawn(None) => (2, 0),
+            TokenType::AlwaysSpawn(Some(spawner)) => {
+                (2, Arc::as_ptr(spawner) as *const () as usize)
+            }
+            TokenType::AlwaysBlockInPlace => (3, 0),
+        }
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            TokenType::Adaptive(state) => match state.name() {
+                Some(name) => f.debug_tuple("Token").field(&name).finish(),
+                None => write!(f, "Token(#{})", state.id()),
+            },
+            TokenType::AlwaysInline => write!(f, "Token(AlwaysInline)"),
+            TokenType::AlwaysSpawn(_) => write!(f, "Token(AlwaysSpawn)"),
+            TokenType::AlwaysBlockInPlace => write!(f, "Token(AlwaysBlockInPlace)"),
+        }
+    }
+}
+
+impl PartialEq for Token {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Token {}
+
+impl Hash for Token {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
 impl Token {
     /// Create a new *unique* `Token`, either to use a `static` or a one-off.
     /// This `Token` is configured to start out work as
     /// *inline in the poll implementation*, and to adaptively switch to spawning.
+    ///
+    /// The state of a one-off `Token` is freed when it (and all its clones) are dropped,
+    /// see [`live_tokens`](super::live_tokens).
     pub fn new() -> Self {
-        Token(TokenType::AdhocAdaptive(
-            CURRENT.fetch_add(1, Ordering::SeqCst),
-        ))
+        Self::builder().build()
+    }
+
+    /// Get the `Token` named `name`, creating it if it doesn't exist yet. This returns the same
+    /// `Token` (sharing the same state) for the same `name` anywhere in the program, so
+    /// different modules (or crates) can share a `Token` without passing it around. Named
+    /// `Token`'s are never freed.
+    ///
+    /// This looks up `name` in a global registry behind a lock, so cache the returned `Token`
+    /// (for example, in a `static`) instead of calling this for every piece of work.
+    ///
+    /// To configure a named `Token`, use [`TokenBuilder::name`].
+    ///
+    /// ```
+    /// use impedance::adaptive::Token;
+    ///
+    /// assert_eq!(Token::named("json-decode"), Token::named("json-decode"));
+    /// assert_eq!(Token::named("json-decode").name(), Some("json-decode"));
+    /// ```
+    pub fn named(name: &str) -> Self {
+        if let Some(token) = REGISTRY.lock().get(name) {
+            return token.clone();
+        }
+        Self::builder().name(name).build()
+    }
+
+    /// The name of this `Token`, if it was created with [`Token::named`] or
+    /// [`TokenBuilder::name`].
+    pub fn name(&self) -> Option<&str> {
+        match &self.0 {
+            TokenType::Adaptive(state) => state.name(),
+            _ => None,
+        }
+    }
+
+    /// A snapshot of what this `Token` has been doing, or `None` for
+    /// [`Token::always_inline`], [`Token::always_spawn`] and [`Token::always_block_in_place`],
+    /// which don't track anything.
+    /// See [`stats`](super::stats) to get the statistics of all `Token`'s.
+    pub fn stats(&self) -> Option<TokenStats> {
+        match &self.0 {
+            TokenType::Adaptive(state) => Some(state.stats()),
+            _ => None,
+        }
+    }
+
+    /// Create a new *unique* `Token` that decides where to run work with a custom
+    /// [`SchedulingPolicy`](super::SchedulingPolicy). [`Token::new`] uses
+    /// [`LastSample`](super::LastSample).
+    pub fn with_policy(policy: impl SchedulingPolicy) -> Self {
+        Self::builder().policy(policy).build()
+    }
+
+    /// Create a [`TokenBuilder`](super::TokenBuilder), to configure a new *unique* `Token`.
+    pub fn builder() -> TokenBuilder {
+        TokenBuilder::default()
+    }
+
+    /// Create a new *unique* `Token` that decides where to run work based on an
+    /// exponentially-weighted moving average of its *wall-times*, instead of only the last
+    /// one. This keeps a single outlier from moving all future work onto a thread (and a
+    /// single fast run from bringing it all back).
+    ///
+    /// `alpha` is the smoothing factor: the weight given to each new sample. Values
+    /// close to `1.0` react quickly, values close to `0.0` smooth out noise more.
+    ///
+    /// # Panics
+    /// If `alpha` is not in `(0.0, 1.0]`
+    pub fn ewma(alpha: f64) -> Self {
+        Self::builder().ewma(alpha).build()
+    }
+
+    /// Create a new *unique* `Token` that uses two separate cutoffs: work is moved onto a
+    /// thread once it takes longer than `spawn_above`, and only brought back inline once it
+    /// takes less than `inline_below`. Either cutoff has to be crossed `min_consecutive`
+    /// times in a row before the `Token` switches.
+    ///
+    /// This keeps work that takes roughly the cutoff time from oscillating between
+    /// running inline and on a thread. Note that this `Token` ignores its configured cutoff.
+    ///
+    /// # Panics
+    /// If `inline_below` is larger than `spawn_above`, or `min_consecutive` is `0`
+    pub fn hysteresis(spawn_above: Duration, inline_below: Duration, min_consecutive: u32) -> Self {
+        Self::builder()
+            .hysteresis(spawn_above, inline_below, min_consecutive)
+            .build()
+    }
+
+    /// Create a new *unique* `Token` that decides where to run work based on the `q`'th
+    /// quantile of its recent *wall-times*, estimated with a compact histogram. For example,
+    /// `Token::quantile(0.99)` moves work onto a thread if more than 1% of it takes longer than
+    /// the cutoff, even if the *typical* piece of work is cheap.
+    ///
+    /// # Panics
+    /// If `q` is not in `(0.0, 1.0]`
+    pub fn quantile(q: f64) -> Self {
+        Self::builder().quantile(q).build()
+    }
+
+    /// Once this `Token` is moving its work onto a thread, run every `calls`'th piece of work
+    /// inline anyways, to re-measure it. This lets work that has gotten cheaper (for example,
+    /// after a cache has warmed up) move back inline. Returns the same `Token`.
+    ///
+    /// This has no effect on [`Token::always_inline`], [`Token::always_spawn`] and
+    /// [`Token::always_block_in_place`].
+    ///
+    /// # Panics
+    /// If `calls` is `0`
+    pub fn probe_every_calls(self, calls: u64) -> Self {
+        assert!(calls > 0, "probe_every_calls must be at least 1");
+        if let TokenType::Adaptive(state) = &self.0 {
+            state.probe_every_calls(calls);
+        }
+        self
+    }
+
+    /// Like [`Token::probe_every_calls`], but re-measures work inline at most once per
+    /// `period`, instead of after a number of calls. Both can be configured on a single `Token`.
+    pub fn probe_every(self, period: Duration) -> Self {
+        if let TokenType::Adaptive(state) = &self.0 {
+            state.probe_every(period);
+        }
+        self
     }
 
     /// Create a new `Token` that tells an [`AdaptiveFuture`](super::AdaptiveFuture) to
@@ -36,7 +226,20 @@ impl Token {
     /// Create a new `Token` that tells an [`AdaptiveFuture`](super::AdaptiveFuture) to
     /// always move its work onto a thread.
     pub fn always_spawn() -> Self {
-        Token(TokenType::AlwaysSpawn)
+        Token(TokenType::AlwaysSpawn(None))
+    }
+
+    /// Like [`Token::always_spawn`], but moves work onto a thread with a custom
+    /// [`BlockingSpawner`](super::BlockingSpawner), like [`TokenBuilder::spawner`] does for
+    /// adaptive `Token`'s.
+    pub fn always_spawn_with(spawner: impl BlockingSpawner) -> Self {
+        Token(TokenType::AlwaysSpawn(Some(Arc::new(spawner))))
+    }
+
+    /// Create a new `Token` that tells an [`AdaptiveFuture`](super::AdaptiveFuture) to
+    /// always run its work with [`AdaptiveState::BlockInPlace`].
+    pub fn always_block_in_place() -> Self {
+        Token(TokenType::AlwaysBlockInPlace)
     }
 }
 impl Default for Token {
@@ -44,3 +247,170 @@ impl Default for Token {
         Self::new()
     }
 }
+
+/// A builder for *unique* adaptive [`Token`](Token)'s, created with [`Token::builder`].
+///
+/// ```
+/// use impedance::adaptive::{AdaptiveState, Token};
+/// use std::time::Duration;
+///
+/// // Latency-critical work that should almost never block the executor
+/// let gateway = Token::builder()
+///     .cutoff(Duration::from_micros(20))
+///     .build();
+///
+/// // Work that we expect to be expensive, but may turn out to be cheap
+/// let batch = Token::builder()
+///     .cutoff(Duration::from_millis(2))
+///     .initial_state(AdaptiveState::Spawn)
+///     .ewma(0.2)
+///     .probe_every_calls(100)
+///     .build();
+/// ```
+#[derive(Default)]
+pub struct TokenBuilder {
+    name: Option<Arc<str>>,
+    policy: Option<Box<dyn SchedulingPolicy>>,
+    cutoff: Option<Duration>,
+    initial_state: Option<AdaptiveState>,
+    probe_every_calls: Option<u64>,
+    probe_every: Option<Duration>,
+    spawner: Option<Box<dyn BlockingSpawner>>,
+}
+
+impl TokenBuilder {
+    /// Name the `Token`, registering it so it can be retrieved with [`Token::named`].
+    ///
+    /// If a `Token` with this name already exists, [`build`](TokenBuilder::build) returns it,
+    /// and the rest of this builder's configuration is ignored. Configure named `Token`'s
+    /// before they are first used, for example at startup.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(Arc::from(name));
+        self
+    }
+
+    /// Set the *wall-time* above which work is considered expensive enough to move onto a
+    /// thread. Defaults to [`BLOCKING_CUTOFF_DURATION`](super::BLOCKING_CUTOFF_DURATION), or
+    /// the cutoff measured by [`calibrate`](super::calibrate).
+    pub fn cutoff(mut self, cutoff: Duration) -> Self {
+        self.cutoff = Some(cutoff);
+        self
+    }
+
+    /// Set where work is run until the `Token` has measured any. By default, this is up to the
+    /// policy, which for the built-in policies is [`AdaptiveState::Inline`].
+    pub fn initial_state(mut self, state: AdaptiveState) -> Self {
+        self.initial_state = Some(state);
+        self
+    }
+
+    /// Use a custom [`SchedulingPolicy`](super::SchedulingPolicy). Defaults to
+    /// [`LastSample`](super::LastSample).
+    pub fn policy(mut self, policy: impl SchedulingPolicy) -> Self {
+        self.policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Use an [`Ewma`](super::Ewma) policy. See [`Token::ewma`].
+    ///
+    /// # Panics
+    /// If `alpha` is not in `(0.0, 1.0]`
+    pub fn ewma(self, alpha: f64) -> Self {
+        self.policy(Ewma::new(alpha))
+    }
+
+    /// Use a [`Hysteresis`](super::Hysteresis) policy. See [`Token::hysteresis`].
+    ///
+    /// # Panics
+    /// If `inline_below` is larger than `spawn_above`, or `min_consecutive` is `0`
+    pub fn hysteresis(
+        self,
+        spawn_above: Duration,
+        inline_below: Duration,
+        min_consecutive: u32,
+    ) -> Self {
+        self.policy(Hysteresis::new(spawn_above, inline_below, min_consecutive))
+    }
+
+    /// Use a [`Quantile`](super::Quantile) policy. See [`Token::quantile`].
+    ///
+    /// # Panics
+    /// If `q` is not in `(0.0, 1.0]`
+    pub fn quantile(self, q: f64) -> Self {
+        self.policy(Quantile::new(q))
+    }
+
+    /// See [`Token::probe_every_calls`].
+    ///
+    /// # Panics
+    /// If `calls` is `0`
+    pub fn probe_every_calls(mut self, calls: u64) -> Self {
+        assert!(calls > 0, "probe_every_calls must be at least 1");
+        self.probe_every_calls = Some(calls);
+        self
+    }
+
+    /// See [`Token::probe_every`].
+    pub fn probe_every(mut self, period: Duration) -> Self {
+        self.probe_every = Some(period);
+        self
+    }
+
+    /// Move work onto rayon's global thread pool, instead of with
+    /// [`spawn_blocking`](tokio::task::spawn_blocking), whose pool is sized for blocking IO and
+    /// oversubscribes the CPU's with CPU-bound work. Panics are propagated like with
+    /// `spawn_blocking`.
+    #[cfg(all(feature = "rayon", feature = "tokio"))]
+    pub fn rayon(self) -> Self {
+        self.spawner(RayonPool::Global)
+    }
+
+    /// Like [`TokenBuilder::rayon`], but with a custom rayon `ThreadPool`.
+    #[cfg(all(feature = "rayon", feature = "tokio"))]
+    pub fn rayon_pool(self, pool: Arc<rayon::ThreadPool>) -> Self {
+        self.spawner(RayonPool::Custom(pool))
+    }
+
+    /// Move work onto a thread with a custom [`BlockingSpawner`](super::BlockingSpawner),
+    /// instead of the `spawn_blocking` of the enabled runtime.
+    pub fn spawner(mut self, spawner: impl BlockingSpawner) -> Self {
+        self.spawner = Some(Box::new(spawner));
+        self
+    }
+
+    /// Create the configured `Token`
+    pub fn build(self) -> Token {
+        match self.name.clone() {
+            Some(name) => {
+                // Hold the lock while building, so the first configuration always wins. The
+                // name is shared between the registry and the `Token`.
+                let mut registry = REGISTRY.lock();
+                registry
+                    .entry(name)
+                    .or_insert_with(|| self.build_unregistered())
+                    .clone()
+            }
+            None => self.build_unregistered(),
+        }
+    }
+
+    fn build_unregistered(self) -> Token {
+        let policy = self
+            .policy
+            .unwrap_or_else(|| Box::new(LastSample::default()));
+        let mut state = TokenState::new(self.name, policy, self.cutoff, self.initial_state);
+        if let Some(spawner) = self.spawner {
+            state.use_spawner(spawner);
+        }
+        if let Some(calls) = self.probe_every_calls {
+            state.probe_every_calls(calls);
+        }
+        if let Some(period) = self.probe_every {
+            state.probe_every(period);
+        }
+
+        let state = Arc::new(state);
+        core::track(&state);
+        Token(TokenType::Adaptive(state))
+    }
+}
@@ -29,21 +29,40 @@
 //! info)
 //!
 //! - `buffer_unordered/buffered` helpers (coming hopefully soon)
-//! Helpers that avoid pitfalls when using `buffer_unordered`.
+//!   Helpers that avoid pitfalls when using `buffer_unordered`.
 //!
 //! ## Features
 //! This library should be design in a way such that any executor that has a
-//! `spawn_blocking` method can be used:
+//! `spawn_blocking` method can be used (see
+//! [`BlockingSpawner`](adaptive::BlockingSpawner) to plug in your own). With
+//! `default-features = false` and none of the runtime features below, work is moved onto a new
+//! thread with [`ThreadSpawner`](adaptive::ThreadSpawner), unless a `Token` is configured with
+//! your own spawner:
 //!
 // TODO(guswynn): can rustdoc auto make these links for me?
 //! - `tokio`: Currently this library tries to provide good support
-//! for [`tokio`](tokio) which is in its `default_features`.
-//! - `async-std-experimental`: This library has experimental support for using [`async-std`](https://docs.rs/async-std) (as well as
-//! [`futures`](https://docs.rs/futures) internally for a oneshot channel). You will need to use `default-features
-//! = false`
-//! and there are caveats: First and foremost, panic payloads's are NOT ALWAYS propagated
-//! correctly, they have a default failed task message when the work was moved to a thread.
+//!   for [`tokio`](tokio) which is in its `default_features`.
+//! - `async-std-experimental`: This library has experimental support for using [`async-std`](https://docs.rs/async-std).
+//!   You will need to use `default-features = false`.
 //!   - TODO: consider [`async_executors`](https://docs.rs/async_executors) for this abstraction
+//! - `smol`: Support for [`smol`](https://docs.rs/smol), moving work onto the thread pool of the
+//!   [`blocking`](https://docs.rs/blocking) crate. You will need to use `default-features = false`.
+//!   Panic payloads are propagated like with `tokio`.
+//! - `tracing`: [`AdaptiveFuture`](adaptive::AdaptiveFuture) emits [`tracing`](https://docs.rs/tracing)
+//!   spans and events describing where work ran, how long it took, and when a
+//!   [`Token`](adaptive::Token) switches between running work inline and on a thread. Work that
+//!   is moved onto a thread still runs inside the caller's current span.
+//! - `metrics`: [`AdaptiveFuture`](adaptive::AdaptiveFuture) records into the installed
+//!   [`metrics`](https://docs.rs/metrics) recorder, with a `token` label that is the name of the
+//!   [`Token`](adaptive::Token) (or `unnamed`, for all of the `Token`'s without one):
+//!   - `impedance_runs_total`: a counter of work run, with a `placement` label that is either
+//!     `inline`, `spawn` or `block_in_place`
+//!   - `impedance_run_duration_seconds`: a histogram of the *wall-time* of work, with a
+//!     `placement` label
+//!   - `impedance_queue_latency_seconds`: a histogram of how long work moved onto a thread
+//!     waited to start
+//!   - `impedance_skipped_total`: a counter of work moved onto a thread that was skipped, as
+//!     its `AdaptiveFuture` was dropped before the work started
 pub mod adaptive;
 
 #[cfg(all(feature = "rayon", feature = "tokio"))]
@@ -54,7 +73,14 @@ pub mod rayon;
 #[cfg(all(test, feature = "tokio"))]
 mod tests {
     use super::*;
-    use adaptive::{AdaptiveFuture, Token};
+    use adaptive::{AdaptiveFuture, AdaptiveState, Outcome, SchedulingPolicy, Token};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
     use tokio::runtime::Handle;
 
     #[tokio::test]
@@ -63,6 +89,445 @@ mod tests {
         assert_eq!(1, thing.await);
     }
 
+    /// Whether `token` ran work that takes `elapsed` inline
+    async fn ran_inline(token: &Token, elapsed: Duration) -> bool {
+        let ran_on = AdaptiveFuture::new(token.clone(), move || {
+            std::thread::sleep(elapsed);
+            std::thread::current().id()
+        })
+        .await;
+        ran_on == std::thread::current().id()
+    }
+
+    #[tokio::test]
+    async fn test_ewma() {
+        let token = Token::builder()
+            .cutoff(Duration::from_millis(1))
+            .ewma(0.5)
+            .build();
+        assert!(ran_inline(&token, Duration::from_millis(4)).await);
+        // The average of the *wall-times* takes a few fast runs to come back under the cutoff
+        assert!(!ran_inline(&token, Duration::ZERO).await);
+        assert!(!ran_inline(&token, Duration::ZERO).await);
+        assert!(!ran_inline(&token, Duration::ZERO).await);
+        assert!(ran_inline(&token, Duration::ZERO).await);
+    }
+
+    #[test]
+    #[should_panic(expected = "ewma smoothing factor")]
+    fn test_ewma_bad_alpha() {
+        Token::ewma(1.5);
+    }
+
+    #[tokio::test]
+    async fn test_hysteresis() {
+        let token = Token::hysteresis(Duration::from_millis(1), Duration::from_micros(500), 2);
+        // Each cutoff has to be crossed twice in a row before the token switches
+        assert!(ran_inline(&token, Duration::from_millis(2)).await);
+        assert!(ran_inline(&token, Duration::from_millis(2)).await);
+        assert!(!ran_inline(&token, Duration::ZERO).await);
+        assert!(!ran_inline(&token, Duration::ZERO).await);
+        assert!(ran_inline(&token, Duration::ZERO).await);
+    }
+
+    #[test]
+    #[should_panic(expected = "must not be larger than spawn_above")]
+    fn test_hysteresis_inverted() {
+        Token::hysteresis(Duration::from_micros(50), Duration::from_micros(150), 3);
+    }
+
+    #[tokio::test]
+    async fn test_quantile() {
+        let token = Token::builder()
+            .cutoff(Duration::from_millis(1))
+            .quantile(0.99)
+            .build();
+        assert!(ran_inline(&token, Duration::ZERO).await);
+        assert!(ran_inline(&token, Duration::from_millis(2)).await);
+        // The slow run is still in the tail, even if the following ones are fast
+        assert!(!ran_inline(&token, Duration::ZERO).await);
+        assert!(!ran_inline(&token, Duration::ZERO).await);
+    }
+
+    #[tokio::test]
+    async fn test_custom_policy() {
+        #[derive(Default)]
+        struct Spawned {
+            decided: AtomicUsize,
+            observed: AtomicUsize,
+        }
+
+        impl SchedulingPolicy for Spawned {
+            fn decide(&self) -> AdaptiveState {
+                self.decided.fetch_add(1, Ordering::SeqCst);
+                AdaptiveState::Spawn
+            }
+
+            fn observe(&self, outcome: &Outcome) {
+                assert_eq!(outcome.placement, AdaptiveState::Spawn);
+                self.observed.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let policy = Arc::new(Spawned::default());
+        let token = Token::with_policy(policy.clone());
+        let spawned_on = AdaptiveFuture::new(token.clone(), || std::thread::current().id()).await;
+        assert_ne!(std::thread::current().id(), spawned_on);
+        assert_eq!(policy.observed.load(Ordering::SeqCst), 1);
+
+        // Reading the stats doesn't ask the policy to decide
+        assert_eq!(token.stats().unwrap().state, AdaptiveState::Spawn);
+        assert_eq!(policy.decided.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_builder_cutoff() {
+        let gateway = Token::builder().cutoff(Duration::from_micros(20)).build();
+        let batch = Token::builder().cutoff(Duration::from_secs(1)).build();
+        let slow = || std::thread::sleep(Duration::from_millis(1));
+        AdaptiveFuture::new(gateway.clone(), slow).await;
+        AdaptiveFuture::new(batch.clone(), slow).await;
+
+        let current = std::thread::current().id();
+        let gateway_on = AdaptiveFuture::new(gateway.clone(), || std::thread::current().id()).await;
+        let batch_on = AdaptiveFuture::new(batch.clone(), || std::thread::current().id()).await;
+        assert_ne!(current, gateway_on);
+        assert_eq!(current, batch_on);
+    }
+
+    #[tokio::test]
+    async fn test_builder_initial_state() {
+        let token = Token::builder().initial_state(AdaptiveState::Spawn).build();
+        let spawned_on = AdaptiveFuture::new(token.clone(), || std::thread::current().id()).await;
+        assert_ne!(std::thread::current().id(), spawned_on);
+
+        // Fast work moves back inline
+        let inline_on = AdaptiveFuture::new(token.clone(), || std::thread::current().id()).await;
+        assert_eq!(std::thread::current().id(), inline_on);
+    }
+
+    #[tokio::test]
+    async fn test_named() {
+        let token = Token::named("test_named");
+        assert_eq!(token.name(), Some("test_named"));
+        assert_eq!(token, Token::named("test_named"));
+        assert_ne!(token, Token::named("test_named_other"));
+        assert_eq!(format!("{:?}", token), r#"Token("test_named")"#);
+
+        // State is shared between all uses of the name
+        AdaptiveFuture::new(token, || std::thread::sleep(Duration::from_millis(1))).await;
+        let spawned_on =
+            AdaptiveFuture::new(Token::named("test_named"), || std::thread::current().id()).await;
+        assert_ne!(std::thread::current().id(), spawned_on);
+    }
+
+    #[tokio::test]
+    async fn test_named_builder() {
+        let configured = Token::builder()
+            .name("test_named_builder")
+            .initial_state(AdaptiveState::Spawn)
+            .build();
+        // Later configuration is ignored
+        let ignored = Token::builder()
+            .name("test_named_builder")
+            .initial_state(AdaptiveState::Inline)
+            .build();
+        assert_eq!(configured, ignored);
+
+        let spawned_on = AdaptiveFuture::new(Token::named("test_named_builder"), || {
+            std::thread::current().id()
+        })
+        .await;
+        assert_ne!(std::thread::current().id(), spawned_on);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let token = Token::named("test_stats");
+        AdaptiveFuture::new(token.clone(), || ()).await;
+        AdaptiveFuture::new(token.clone(), || {
+            std::thread::sleep(Duration::from_millis(1))
+        })
+        .await;
+        AdaptiveFuture::new(token.clone(), || ()).await;
+        AdaptiveFuture::new(token.clone(), || ()).await;
+
+        let stats = token.stats().unwrap();
+        assert_eq!(stats.name.as_deref(), Some("test_stats"));
+        assert_eq!(stats.state, AdaptiveState::Inline);
+        assert_eq!(stats.inline_runs, 3);
+        assert_eq!(stats.spawned_runs, 1);
+        assert_eq!(stats.switches, 2);
+        assert!(stats.max_time >= Duration::from_millis(1));
+        assert!(stats.total_time >= stats.max_time);
+        assert_eq!(stats.mean_time, stats.total_time / 4);
+
+        assert!(adaptive::stats().contains(&stats));
+        assert_eq!(Token::always_inline().stats(), None);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_spawned_in_callers_span() {
+        use tracing::{dispatcher, Instrument, Span};
+        use tracing_subscriber::registry::{LookupSpan, Registry};
+
+        // The blocking thread doesn't inherit a scoped default subscriber
+        let _ = tracing::subscriber::set_global_default(Registry::default());
+
+        let parents = AdaptiveFuture::new(Token::always_spawn(), || {
+            let id = Span::current().id().unwrap();
+            dispatcher::get_default(|dispatch| {
+                let registry = dispatch.downcast_ref::<Registry>().unwrap();
+                let span = registry.span(&id).unwrap();
+                span.scope().map(|span| span.name()).collect::<Vec<_>>()
+            })
+        })
+        .instrument(tracing::info_span!("caller"))
+        .await;
+
+        assert_eq!(parents, vec!["adaptive_future", "caller"]);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        use metrics_util::{
+            debugging::{DebugValue, DebuggingRecorder},
+            MetricKind,
+        };
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        // Inline work runs on this thread, which the local recorder is installed on
+        metrics::with_local_recorder(&recorder, || {
+            futures::executor::block_on(AdaptiveFuture::new(
+                Token::builder()
+                    .name("test_metrics")
+                    .initial_state(AdaptiveState::Inline)
+                    .build(),
+                || (),
+            ));
+            for _ in 0..2 {
+                futures::executor::block_on(AdaptiveFuture::new(Token::new(), || ()));
+            }
+        });
+
+        let metrics: Vec<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| (key, value))
+            .collect();
+        assert!(metrics.iter().any(|(key, value)| {
+            key.kind() == MetricKind::Counter
+                && key.key().name() == "impedance_runs_total"
+                && key
+                    .key()
+                    .labels()
+                    .any(|l| l.key() == "token" && l.value() == "test_metrics")
+                && key
+                    .key()
+                    .labels()
+                    .any(|l| l.key() == "placement" && l.value() == "inline")
+                && *value == DebugValue::Counter(1)
+        }));
+        // Unnamed `Token`'s share a label
+        assert!(metrics.iter().any(|(key, value)| {
+            key.kind() == MetricKind::Counter
+                && key.key().name() == "impedance_runs_total"
+                && key
+                    .key()
+                    .labels()
+                    .any(|l| l.key() == "token" && l.value() == "unnamed")
+                && *value == DebugValue::Counter(2)
+        }));
+        assert!(metrics.iter().any(|(key, _)| {
+            key.kind() == MetricKind::Histogram
+                && key.key().name() == "impedance_run_duration_seconds"
+        }));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_block_in_place() {
+        let thread = std::thread::current().id();
+        let ran_on = AdaptiveFuture::new(Token::always_block_in_place(), || {
+            std::thread::current().id()
+        })
+        .await;
+        assert_eq!(ran_on, thread);
+
+        let token = Token::builder()
+            .initial_state(AdaptiveState::BlockInPlace)
+            .build();
+        let ran_on = AdaptiveFuture::new(token.clone(), || std::thread::current().id()).await;
+        assert_eq!(ran_on, thread);
+        assert_eq!(token.stats().unwrap().blocked_in_place_runs, 1);
+    }
+
+    #[tokio::test]
+    async fn test_block_in_place_falls_back() {
+        let thread = std::thread::current().id();
+        let ran_on = AdaptiveFuture::new(Token::always_block_in_place(), || {
+            std::thread::current().id()
+        })
+        .await;
+        assert_ne!(ran_on, thread);
+    }
+
+    #[tokio::test]
+    async fn test_custom_spawner() {
+        use adaptive::{BlockingSpawner, BlockingWork, TokioSpawner};
+
+        #[derive(Default)]
+        struct Counting(AtomicUsize);
+
+        impl BlockingSpawner for Counting {
+            fn spawn(&self, work: BlockingWork) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                TokioSpawner.spawn(work)
+            }
+        }
+
+        let spawner = Arc::new(Counting::default());
+        let token = Token::builder()
+            .initial_state(AdaptiveState::Spawn)
+            .spawner(spawner.clone())
+            .build();
+        assert_eq!(1, AdaptiveFuture::new(token, || 1).await);
+        assert_eq!(spawner.0.load(Ordering::SeqCst), 1);
+
+        let token = Token::always_spawn_with(spawner.clone());
+        assert_eq!(token, token.clone());
+        assert_ne!(token, Token::always_spawn());
+        assert_eq!(2, AdaptiveFuture::new(token, || 2).await);
+        assert_eq!(spawner.0.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cancellable() {
+        use std::sync::mpsc;
+
+        let (started_tx, started_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+        let thing = AdaptiveFuture::cancellable(Token::always_spawn(), move |cancellation| {
+            started_tx.send(()).unwrap();
+            while !cancellation.is_cancelled() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            done_tx.send(()).unwrap();
+        });
+        let mut thing = Box::pin(thing);
+
+        assert!(futures::poll!(&mut thing).is_pending());
+        started_rx.recv().unwrap();
+        drop(thing);
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancellable_inline() {
+        let cancelled = AdaptiveFuture::cancellable(Token::always_inline(), |cancellation| {
+            cancellation.is_cancelled()
+        })
+        .await;
+        assert!(!cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_skip_dropped() {
+        use adaptive::{BlockingSpawner, BlockingWork};
+        use std::sync::Mutex;
+
+        /// Holds on to work until the test runs it
+        #[derive(Default)]
+        struct Queue(Mutex<Vec<BlockingWork>>);
+
+        impl BlockingSpawner for Queue {
+            fn spawn(&self, work: BlockingWork) {
+                self.0.lock().unwrap().push(work);
+            }
+        }
+
+        let queue = Arc::new(Queue::default());
+        let token = Token::builder()
+            .initial_state(AdaptiveState::Spawn)
+            .spawner(queue.clone())
+            .build();
+        let ran = Arc::new(AtomicUsize::new(0));
+        let thing = AdaptiveFuture::new(token.clone(), {
+            let ran = ran.clone();
+            move || ran.fetch_add(1, Ordering::SeqCst)
+        });
+        let mut thing = Box::pin(thing);
+
+        assert!(futures::poll!(&mut thing).is_pending());
+        drop(thing);
+        for work in queue.0.lock().unwrap().drain(..) {
+            work();
+        }
+
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+        let stats = token.stats().unwrap();
+        assert_eq!(stats.skipped_runs, 1);
+        assert_eq!(stats.spawned_runs, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cost_hint() {
+        let token = Token::new();
+        // 1ms per 1000 units, or 1us per unit
+        let work = |size| move || std::thread::sleep(Duration::from_micros(size as u64));
+        AdaptiveFuture::with_cost_hint(token.clone(), 1000, work(1000)).await;
+
+        // Small work is inlined, even though the last work was slow
+        let small_on =
+            AdaptiveFuture::with_cost_hint(token.clone(), 1, || std::thread::current().id()).await;
+        assert_eq!(std::thread::current().id(), small_on);
+
+        // Large work is spawned, even though the last work was fast
+        let large_on =
+            AdaptiveFuture::with_cost_hint(token, 1000, || std::thread::current().id()).await;
+        assert_ne!(std::thread::current().id(), large_on);
+    }
+
+    #[tokio::test]
+    async fn test_one_off_tokens_freed() {
+        let before = adaptive::live_tokens();
+        for i in 0..10_000 {
+            assert_eq!(i, AdaptiveFuture::new(Token::new(), move || i).await);
+        }
+        // Spawned work holds onto its `Token` until its done
+        for i in 0..100 {
+            let one_off = Token::builder().initial_state(AdaptiveState::Spawn).build();
+            assert_eq!(i, AdaptiveFuture::new(one_off, move || i).await);
+        }
+
+        // Other tests may be creating `Token`'s concurrently
+        assert!(adaptive::live_tokens() < before + 100);
+    }
+
+    #[tokio::test]
+    async fn test_measure_overhead() {
+        let calibration = adaptive::measure_overhead().await;
+        assert!(calibration.overhead > Duration::from_nanos(0));
+        assert_eq!(calibration.cutoff, calibration.overhead * 2);
+        // Measuring doesn't change the default cutoff
+        assert_eq!(adaptive::calibration(), None);
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let token = Token::new().probe_every_calls(2);
+        let slow = || std::thread::sleep(Duration::from_millis(1));
+        AdaptiveFuture::new(token.clone(), slow).await;
+        AdaptiveFuture::new(token.clone(), slow).await;
+
+        // The 2nd piece of work spawned is inlined instead
+        let probed_on = AdaptiveFuture::new(token.clone(), || std::thread::current().id()).await;
+        assert_eq!(std::thread::current().id(), probed_on);
+    }
+
     #[tokio::test]
     #[should_panic(expected = "Cannot start a runtime from within a runtime")]
     async fn test_nested() {
@@ -74,6 +539,7 @@ mod tests {
 
     #[tokio::test]
     #[should_panic(expected = "Cannot start a runtime from within a runtime")]
+    #[allow(clippy::redundant_closure_call)]
     async fn test_nested_comparison() {
         let thing = (|| {
             Handle::current().block_on(async { AdaptiveFuture::new(Token::new(), || 1).await })
@@ -106,6 +572,100 @@ mod tests {
         });
         assert_eq!(1, thing.await);
     }
+
+    #[cfg(feature = "rayon")]
+    #[tokio::test]
+    async fn test_rayon_pool() {
+        let pool = ::rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .thread_name(|_| "test_rayon_pool".to_string())
+            .build()
+            .unwrap();
+        let token = Token::builder()
+            .initial_state(AdaptiveState::Spawn)
+            .rayon_pool(Arc::new(pool))
+            .build();
+
+        let thread = AdaptiveFuture::new(token.clone(), || {
+            std::thread::current().name().map(str::to_string)
+        })
+        .await;
+        assert_eq!(thread.as_deref(), Some("test_rayon_pool"));
+        assert_eq!(token.stats().unwrap().spawned_runs, 1);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[tokio::test]
+    #[should_panic(expected = "gus")]
+    async fn test_panic_rayon() {
+        let token = Token::builder()
+            .initial_state(AdaptiveState::Spawn)
+            .rayon()
+            .build();
+        let thing = AdaptiveFuture::new(token, || {
+            if false {
+                1_isize
+            } else {
+                panic!("gus");
+            }
+        });
+        assert_eq!(1, thing.await);
+    }
+
+    #[tokio::test]
+    async fn test_panic_payload() {
+        use futures::FutureExt;
+        use std::panic::{panic_any, AssertUnwindSafe};
+
+        let thing = AdaptiveFuture::new(Token::always_spawn(), || -> isize { panic_any(42_usize) });
+        let panic = AssertUnwindSafe(thing).catch_unwind().await.unwrap_err();
+        assert_eq!(panic.downcast_ref::<usize>(), Some(&42));
+    }
+
+    #[tokio::test]
+    async fn test_try_new() {
+        use std::panic::panic_any;
+
+        let thing = AdaptiveFuture::try_new(Token::new(), || 1);
+        assert_eq!(1, thing.await.unwrap());
+
+        for token in &[Token::always_inline(), Token::always_spawn()] {
+            let thing = AdaptiveFuture::try_new(token.clone(), || -> isize { panic_any(42_usize) });
+            let panic = thing.await.unwrap_err().into_panic();
+            assert_eq!(panic.downcast_ref::<usize>(), Some(&42));
+        }
+    }
+
+    #[test]
+    fn test_try_new_runtime_shutdown() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let handle = rt.handle().clone();
+        // The runtime drops work spawned while it is shutting down, without running it
+        rt.shutdown_background();
+
+        let _enter = handle.enter();
+        let thing = AdaptiveFuture::try_new(Token::always_spawn(), || 1);
+        assert!(futures::executor::block_on(thing)
+            .unwrap_err()
+            .is_cancelled());
+
+        // The infallible `AdaptiveFuture` has nothing to return, so it keeps pending
+        let mut thing = Box::pin(AdaptiveFuture::new(Token::always_spawn(), || 1));
+        futures::executor::block_on(async {
+            assert!(futures::poll!(&mut thing).is_pending());
+            assert!(futures::poll!(&mut thing).is_pending());
+        });
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "re-polled a Ready Future")]
+    async fn test_repoll_spawned() {
+        let mut thing = Box::pin(AdaptiveFuture::new(Token::always_spawn(), || 1));
+        assert_eq!(1, (&mut thing).await);
+        let _ = futures::poll!(&mut thing);
+    }
 }
 
 #[cfg(all(test, feature = "async-std-experimental"))]
@@ -133,7 +693,7 @@ mod async_std_tests {
     }
 
     #[async_std::test]
-    #[should_panic(expected = "task has failed")]
+    #[should_panic(expected = "gus")]
     async fn test_panic_spawning() {
         let thing = AdaptiveFuture::new(Token::always_spawn(), || {
             if false {
@@ -144,4 +704,101 @@ mod async_std_tests {
         });
         assert_eq!(1, thing.await);
     }
+
+    #[async_std::test]
+    async fn test_panic_payload() {
+        use futures::FutureExt;
+        use std::panic::{panic_any, AssertUnwindSafe};
+
+        let thing = AdaptiveFuture::new(Token::always_spawn(), || -> isize { panic_any(42_usize) });
+        let panic = AssertUnwindSafe(thing).catch_unwind().await.unwrap_err();
+        assert_eq!(panic.downcast_ref::<usize>(), Some(&42));
+    }
+}
+
+#[cfg(all(test, feature = "smol"))]
+mod smol_tests {
+    use super::*;
+    use adaptive::{AdaptiveFuture, Token};
+
+    #[test]
+    fn test_basic() {
+        smol::block_on(async {
+            let thing = AdaptiveFuture::new(Token::new(), || 1);
+            assert_eq!(1, thing.await);
+        })
+    }
+
+    #[test]
+    fn test_spawning() {
+        smol::block_on(async {
+            let thing = AdaptiveFuture::new(Token::always_spawn(), || 1);
+            assert_eq!(1, thing.await);
+        })
+    }
+
+    #[test]
+    #[should_panic(expected = "gus")]
+    fn test_panic_adaptive() {
+        smol::block_on(async {
+            let thing = AdaptiveFuture::new(Token::new(), || {
+                if false {
+                    1_isize
+                } else {
+                    panic!("gus");
+                }
+            });
+            assert_eq!(1, thing.await);
+        })
+    }
+
+    #[test]
+    #[should_panic(expected = "gus")]
+    fn test_panic_spawning() {
+        smol::block_on(async {
+            let thing = AdaptiveFuture::new(Token::always_spawn(), || {
+                if false {
+                    1_isize
+                } else {
+                    panic!("gus");
+                }
+            });
+            assert_eq!(1, thing.await);
+        })
+    }
+
+    #[test]
+    fn test_panic_payload() {
+        use futures::FutureExt;
+        use std::panic::{panic_any, AssertUnwindSafe};
+
+        smol::block_on(async {
+            let thing =
+                AdaptiveFuture::new(Token::always_spawn(), || -> isize { panic_any(42_usize) });
+            let panic = AssertUnwindSafe(thing).catch_unwind().await.unwrap_err();
+            assert_eq!(panic.downcast_ref::<usize>(), Some(&42));
+        })
+    }
+}
+
+#[cfg(all(
+    test,
+    not(any(
+        feature = "tokio",
+        feature = "async-std-experimental",
+        feature = "smol"
+    ))
+))]
+mod no_runtime_tests {
+    use super::*;
+    use adaptive::{AdaptiveFuture, Token};
+
+    #[test]
+    fn test_spawning() {
+        let spawned_on =
+            futures::executor::block_on(AdaptiveFuture::new(Token::always_spawn(), || {
+                std::thread::current().id()
+            }));
+        assert_ne!(std::thread::current().id(), spawned_on);
+    }
 }
@@ -1,9 +1,29 @@
-use rayon::iter::IntoParallelIterator;
+use rayon::{iter::IntoParallelIterator, ThreadPool};
 use std::{
     any::Any,
     fmt::{self, Debug},
+    sync::Arc,
 };
 
+use crate::adaptive::{BlockingSpawner, BlockingWork};
+
+/// The rayon pool an adaptive [`Token`](crate::adaptive::Token) moves its work onto
+#[derive(Clone)]
+pub(crate) enum RayonPool {
+    Global,
+    Custom(Arc<ThreadPool>),
+}
+
+impl BlockingSpawner for RayonPool {
+    fn spawn(&self, work: BlockingWork) {
+        // `BlockingWork` catches its own panics, so rayon won't abort
+        match self {
+            RayonPool::Global => rayon::spawn(work),
+            RayonPool::Custom(pool) => pool.spawn(work),
+        }
+    }
+}
+
 pub async fn par_iter<T, R, F>(t: T, closure: F) -> Result<R, Panicked>
 where
     T: IntoParallelIterator + Send + 'static,
@@ -80,7 +100,7 @@ mod tests {
         tokio::select! {
             biased;
             _ = &mut par_iter => {
-                assert!(false, "Shouldn't make it here")
+                unreachable!("Shouldn't make it here")
             }
             _ = async_sleep => {
                 eprintln!("made it here sleep");
@@ -0,0 +1,47 @@
+//! Calibrating replaces the process-global default cutoff, so this runs in its own test binary,
+//! where it can't change the placement of the work of other tests.
+#![cfg(feature = "tokio")]
+
+use impedance::adaptive::{
+    calibrate, calibration, AdaptiveFuture, AdaptiveState, Outcome, SchedulingPolicy, Token,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Records the cutoff the `Token` passes it
+#[derive(Default)]
+struct Cutoff(Mutex<Option<Duration>>);
+
+impl SchedulingPolicy for Cutoff {
+    fn decide(&self) -> AdaptiveState {
+        AdaptiveState::Inline
+    }
+
+    fn observe(&self, outcome: &Outcome) {
+        *self.0.lock().unwrap() = Some(outcome.cutoff);
+    }
+}
+
+#[tokio::test]
+async fn test_calibrate() {
+    assert_eq!(calibration(), None);
+
+    let calibrated = calibrate().await;
+    assert_eq!(calibration(), Some(calibrated));
+    assert!(calibrated.cutoff >= calibrated.overhead);
+
+    // `Token`'s without their own cutoff use the calibrated one
+    let policy = Arc::new(Cutoff::default());
+    AdaptiveFuture::new(Token::with_policy(policy.clone()), || ()).await;
+    assert_eq!(*policy.0.lock().unwrap(), Some(calibrated.cutoff));
+
+    let configured = Arc::new(Cutoff::default());
+    let token = Token::builder()
+        .cutoff(Duration::from_secs(1))
+        .policy(configured.clone())
+        .build();
+    AdaptiveFuture::new(token, || ()).await;
+    assert_eq!(*configured.0.lock().unwrap(), Some(Duration::from_secs(1)));
+}
@@ -0,0 +1,36 @@
+//! The inline budget is process-global, so this runs in its own test binary, where it can't
+//! move the work of other tests onto threads.
+#![cfg(feature = "tokio")]
+
+use impedance::adaptive::{set_inline_budget, AdaptiveFuture, InlineBudget, Token};
+use std::{
+    thread::{self, ThreadId},
+    time::Duration,
+};
+
+async fn run_on(token: &Token) -> ThreadId {
+    AdaptiveFuture::new(token.clone(), || {
+        thread::sleep(Duration::from_millis(2));
+        thread::current().id()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_inline_budget() {
+    let window = Duration::from_millis(500);
+    set_inline_budget(Some(InlineBudget::new(Duration::from_millis(1), window)));
+
+    // The work is always cheaper than the cutoff, so it only moves because of the budget
+    let token = Token::builder().cutoff(Duration::from_secs(1)).build();
+    let this_thread = thread::current().id();
+
+    assert_eq!(run_on(&token).await, this_thread);
+    // That used up the budget
+    assert_ne!(run_on(&token).await, this_thread);
+
+    tokio::time::sleep(window).await;
+    assert_eq!(run_on(&token).await, this_thread);
+
+    set_inline_budget(None);
+}